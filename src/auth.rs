@@ -0,0 +1,193 @@
+//! Pre-shared-key authentication of the sender by the receiver.
+//!
+//! When `syncrd` is given a secrets file, it advertises the
+//! [`AUTHENTICATION`](crate::handshake::AUTHENTICATION) feature and answers
+//! the client's `Hello` with a random nonce (`AuthChallenge`). The client
+//! proves it knows its user's secret by replying with the HMAC-SHA256 of that
+//! nonce keyed by the secret (`AuthResponse`), before it may name any file.
+//!
+//! The secrets file has one `user:secret` pair per line, like rsyncd's
+//! `secrets file`. Blank lines and lines starting with `#` are ignored.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::SyncrError;
+
+/// The length of the receiver's nonce, in bytes.
+pub const NONCE_LENGTH: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+
+/// A fresh random nonce to challenge the sender with.
+pub fn challenge() -> Vec<u8> {
+    rand::random::<[u8; NONCE_LENGTH]>().to_vec()
+}
+
+
+fn mac(secret: &str, nonce: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(nonce);
+    mac
+}
+
+
+/// The user the sender authenticates as, and their secret.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub user: String,
+    pub secret: String,
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials").field("user", &self.user).finish_non_exhaustive()
+    }
+}
+
+impl Credentials {
+    /// Read the secret from the first line of a password file.
+    pub fn from_password_file<P: AsRef<Path>>(user: String, path: P) -> crate::Result<Self> {
+        let contents = read_private_file(path.as_ref())?;
+        let secret = contents.lines().next().unwrap_or_default().to_string();
+        Ok(Self { user, secret })
+    }
+
+    /// Answer the receiver's challenge.
+    pub fn respond(&self, nonce: &[u8]) -> Vec<u8> {
+        mac(&self.secret, nonce).finalize().into_bytes().to_vec()
+    }
+}
+
+
+/// The secrets of every user allowed to authenticate with the receiver.
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secrets {
+    secrets: HashMap<String, String>,
+}
+
+impl std::fmt::Debug for Secrets {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_set().entries(self.secrets.keys()).finish()
+    }
+}
+
+impl Secrets {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref();
+        Self::parse(&read_private_file(path)?).map_err(|reason| SyncrError::InvalidSecretsFile {
+            path: path.display().to_string(),
+            reason,
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let mut secrets = HashMap::new();
+        for (number, line) in contents.lines().enumerate() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, secret)) if !user.is_empty() => {
+                    secrets.insert(user.to_string(), secret.to_string());
+                },
+                _ => return Err(format!("line {} is not of the form user:secret", number + 1)),
+            }
+        }
+        Ok(Self { secrets })
+    }
+
+    /// Check that `mac` is `user`'s answer to our challenge `nonce`.
+    pub fn verify(&self, user: &str, nonce: &[u8], mac_bytes: &[u8]) -> crate::Result<()> {
+        let secret = self
+            .secrets
+            .get(user)
+            .ok_or_else(|| SyncrError::AuthenticationFailed(user.to_string()))?;
+        mac(secret, nonce)
+            .verify_slice(mac_bytes)
+            .map_err(|_| SyncrError::AuthenticationFailed(user.to_string()))
+    }
+}
+
+
+/// Read a file holding secrets, refusing to use it if other users can access
+/// it (like rsync's `strict modes`).
+fn read_private_file(path: &Path) -> crate::Result<String> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(path)?.permissions().mode();
+        if mode & 0o007 != 0 {
+            return Err(SyncrError::InvalidSecretsFile {
+                path: path.display().to_string(),
+                reason: "it must not be accessible by other users".to_string(),
+            });
+        }
+    }
+    Ok(std::fs::read_to_string(path)?)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials(user: &str, secret: &str) -> Credentials {
+        Credentials {
+            user: user.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+
+    #[test]
+    fn secrets_are_parsed() {
+        let secrets = Secrets::parse("# comment\n\nalice:s3cr:et\nbob:\n").unwrap();
+        assert_eq!(secrets.secrets.get("alice").unwrap(), "s3cr:et");
+        assert_eq!(secrets.secrets.get("bob").unwrap(), "");
+
+        assert!(Secrets::parse("alice").is_err());
+        assert!(Secrets::parse(":secret").is_err());
+    }
+
+    #[test]
+    fn only_the_right_secret_is_accepted() {
+        let secrets = Secrets::parse("alice:secret\n").unwrap();
+        let nonce = challenge();
+
+        let response = credentials("alice", "secret").respond(&nonce);
+        assert!(secrets.verify("alice", &nonce, &response).is_ok());
+
+        let response = credentials("alice", "guess").respond(&nonce);
+        assert!(matches!(secrets.verify("alice", &nonce, &response), Err(SyncrError::AuthenticationFailed(_))));
+
+        let response = credentials("mallory", "secret").respond(&nonce);
+        assert!(matches!(secrets.verify("mallory", &nonce, &response), Err(SyncrError::AuthenticationFailed(_))));
+    }
+
+    #[test]
+    fn responses_are_tied_to_the_nonce() {
+        let secrets = Secrets::parse("alice:secret\n").unwrap();
+        let response = credentials("alice", "secret").respond(&challenge());
+        assert!(secrets.verify("alice", &challenge(), &response).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn world_readable_secrets_are_refused() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("syncr-secrets-test-{}", std::process::id()));
+        std::fs::write(&path, "alice:secret\n").unwrap();
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(Secrets::from_file(&path), Err(SyncrError::InvalidSecretsFile { .. })));
+
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(Secrets::from_file(&path).is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,14 +1,17 @@
 use clap::Parser;
-use clap::command;
 use syncr::CheckSum;
 use syncr::ChecksumConfig;
 use syncr::Checksums;
+use syncr::file_data::FileData;
 use syncr::multisearch::Matcher;
+use syncr::rolling_hash::RollingHashAlgorithm;
+use syncr::strong_checksum::StrongHashAlgorithm;
+use syncr::streaming::StreamingChecksums;
 use std::io::BufWriter;
 use std::io::Write;
 use std::path::PathBuf;
 use std::vec::Vec;
-use clap::{Args, Subcommand, ValueEnum};
+use clap::Subcommand;
 
 #[derive(Parser, Debug)]
 #[command(name = "syncr")]
@@ -23,6 +26,10 @@ pub struct Opts {
     block_size: usize,
     #[clap(short, long, help = "The modulus to use for the checksum.", default_value_t = 1 << 16)]
     modulus: u32,
+    #[clap(long, value_enum, help = "The rolling hash to use for the weak checksums.", default_value_t = RollingHashAlgorithm::default())]
+    rolling_hash: RollingHashAlgorithm,
+    #[clap(long, value_enum, help = "The strong hash to use.", default_value_t = StrongHashAlgorithm::default())]
+    strong_hash: StrongHashAlgorithm,
 
     #[command(subcommand)]
     command: Commands,
@@ -54,24 +61,27 @@ pub fn main() {
     match args.command {
         Commands::Checksum { files, strong } => {
             for file in files {
-                let buffer = std::fs::read(file).unwrap();
+                let file = std::fs::File::open(file).unwrap();
 
                 let config = ChecksumConfig {
                     block_size: args.block_size,
                     modulus: args.modulus,
+                    rolling_hash: args.rolling_hash,
+                    strong_hash: args.strong_hash,
+                    ..Default::default()
                 };
 
                 let checksum = CheckSum::with_config(&config);
                 match strong {
                     true => {
-                        for checksum in checksum.strong.checksums(&buffer) {
-                            write!(writer, "{}", checksum).unwrap();
+                        for checksum in checksum.strong.read_checksums(file) {
+                            write!(writer, "{}", checksum.unwrap()).unwrap();
                         }
                         writer.flush().unwrap();
                     },
                     false => {
-                        for checksum in checksum.weak.checksums(&buffer) {
-                            write!(writer, "{}", checksum).unwrap();
+                        for checksum in checksum.weak.read_checksums(file) {
+                            write!(writer, "{}", checksum.unwrap()).unwrap();
                         }
                         writer.flush().unwrap();
                     }
@@ -79,17 +89,22 @@ pub fn main() {
             }
         },
         Commands::Diff { file_to_update, file_to_update_with } => {
-            let client_buffer = std::fs::read(file_to_update_with.clone()).unwrap();
-            let server_buffer = std::fs::read(file_to_update.clone()).unwrap();
+            let client_buffer = FileData::open(&file_to_update_with).unwrap();
+            let server_buffer = FileData::open(&file_to_update).unwrap();
 
             let config = ChecksumConfig {
                 block_size: args.block_size,
                 modulus: args.modulus,
+                rolling_hash: args.rolling_hash,
+                strong_hash: args.strong_hash,
+                ..Default::default()
             };
 
             let checksum = CheckSum::with_config(&config);
-            let mut matcher = Matcher::default();
-            matcher.checksum = checksum;
+            let mut matcher = Matcher {
+                checksum,
+                ..Default::default()
+            };
 
             matcher.compile(&client_buffer);
 
@@ -1,48 +1,396 @@
-//! Sender sends all the weak rolling checksums to the receiver.
-//! Receiver computes weak non-overlapping checksums and if they match
-//! it requests the sender to compute the strong checksums for the matching
-//! blocks.
-//! Sender computes the strong checksums and sends them to the receiver.
-//! Receiver compares the strong checksums and if they match, the receiver
-//! knows that it has blocks that are identical to the sender. It also knows
-//! the matching offsets of the blocks from the sender's file. A run of matching
-//! blocks can be coalesced into a single matching block.
-//! Then the receiver requests the sender to send everything 
-//! but the matching blocks.
-
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
+//! Both peers start by exchanging a `Hello` and agreeing on the parameters
+//! of the session (see `syncr::handshake`).
+//! Sender sends the name of the file it wants to update on the receiver.
+//! Receiver computes the weak and strong checksums of its non-overlapping
+//! blocks and sends these block signatures to the sender.
+//! Sender rolls over every byte offset of its own file looking for those
+//! blocks, so it knows which regions of its file the receiver already has.
+//! A run of matching blocks can be coalesced into a single matching block.
+//! Then the sender sends instructions to rebuild its file from the receiver's
+//! blocks and literal data for everything but the matching blocks.
+//!
+//! `syncrd` listens for clients on TCP, or on a Unix domain socket with
+//! `--socket`. With `--server` it speaks to a single client over stdin/stdout
+//! instead (e.g. when spawned with `ssh host syncrd --server` by
+//! `syncr --rsh`). With the `tls` feature, TCP connections can be encrypted
+//! with `--tls-cert` and `--tls-key`. Clients are served concurrently, up to
+//! `--max-connections` at a time, and dropped if they go quiet for
+//! `--timeout` seconds.
+//!
+//! Clients name files relative to the `--root` directory, or as `module/path`
+//! for one of the modules in the `--config` file (see `syncr::config`). They
+//! may not access anything outside of it (see `syncr::sandbox`).
+//!
+//! With `--secrets-file`, clients must authenticate (see `syncr::auth`)
+//! before they may name a file.
+//!
+//! When started by a systemd socket unit, `syncrd` serves clients on the
+//! socket it was passed (TCP or Unix) instead of binding its own (see
+//! `syncr::systemd`).
+//!
+//! On SIGTERM (or Ctrl-C), `syncrd` stops accepting clients and gives the
+//! sessions in flight `--shutdown-timeout` seconds to finish, before aborting
+//! them with an error telling their clients to try again later. On SIGHUP it
+//! reloads its configuration and secrets; sessions in flight keep the
+//! settings they started with.
+
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::ops::ControlFlow;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 use tokio::net::{
     TcpListener,
     TcpStream,
 };
-use syncr::{network::*, CheckSum, Checksums, strong_checksum::hash as strong_hash, multisearch::weak_hash};
-use tracing::{info, error};
-use tracing_subscriber;
+use syncr::{network::*, auth::{self, Secrets}, config::{Config, ConnectionLimit, ConnectionSlot, Modules}, locks::{FileLock, FileLocks}, delta, file_data::FileData, handshake::{self, Agreement, Hello}, session::ReceiverState, SyncrError, CheckSum, ChecksumConfig, Checksums};
+use tracing::{info, warn, error};
+use clap::Parser;
+
+
+#[derive(Debug, Clone, Parser)]
+pub struct Cli {
+    #[clap(long, help = "Serve a single client over stdin/stdout instead of listening on TCP.")]
+    pub server: bool,
+    #[cfg(unix)]
+    #[clap(long, help = "Listen on a Unix domain socket at this path instead of TCP.", conflicts_with = "server")]
+    pub socket: Option<std::path::PathBuf>,
+    #[cfg(unix)]
+    #[clap(
+        long,
+        help = "The permissions of the Unix domain socket, in octal.",
+        value_parser = parse_mode,
+        default_value = "660",
+        requires = "socket"
+    )]
+    pub socket_mode: u32,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "Serve TCP connections over TLS with the certificate chain in this PEM file.", requires = "tls_key", conflicts_with = "server")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_cert: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "The private key of the TLS certificate, in PEM.", requires = "tls_cert", conflicts_with = "server")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_key: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "Require clients to present a certificate signed by a CA in this PEM bundle.", requires = "tls_cert", conflicts_with = "server")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_client_ca: Option<std::path::PathBuf>,
+    #[clap(long, help = "Read the address, secrets file and modules to serve from this TOML file.")]
+    pub config: Option<std::path::PathBuf>,
+    #[clap(long, help = "The address to listen on for TCP connections [default: 0.0.0.0:8000].")]
+    pub address: Option<String>,
+    #[clap(
+        long,
+        help = "The directory to serve files from, if there are no modules. Clients can't access anything outside of it.",
+        default_value = ".",
+        conflicts_with = "config"
+    )]
+    pub root: std::path::PathBuf,
+    #[clap(long, help = "The maximum number of clients served at the same time [default: 64].")]
+    pub max_connections: Option<usize>,
+    #[clap(long, help = "Require clients to authenticate as one of the users in this file of user:secret lines.")]
+    pub secrets_file: Option<std::path::PathBuf>,
+    #[clap(long, help = "How long to let sessions finish when shutting down before aborting them, in seconds.", default_value_t = 30)]
+    pub shutdown_timeout: u64,
+    #[clap(long, help = "How long to wait for the next message from a client (or for its TLS handshake) before hanging up, in seconds.", default_value_t = 300)]
+    pub timeout: u64,
+    #[clap(long, help = "The maximum size of a single incoming frame, in bytes.", default_value_t = Limits::default().max_frame_size)]
+    pub max_frame_size: usize,
+    #[clap(long, help = "The maximum number of block signatures in a single message.", default_value_t = Limits::default().max_signatures)]
+    pub max_signatures: usize,
+    #[clap(long, help = "The maximum number of instructions in a single message.", default_value_t = Limits::default().max_instructions)]
+    pub max_instructions: usize,
+    #[clap(long, help = "The maximum number of literal bytes a client may send per session.", default_value_t = Limits::default().max_literal_bytes)]
+    pub max_literal_bytes: usize,
+    #[clap(long, help = "The maximum size of a file rebuilt from a client's instructions.", default_value_t = Limits::default().max_file_size)]
+    pub max_file_size: usize,
+}
+
+impl Cli {
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_frame_size: self.max_frame_size,
+            max_signatures: self.max_signatures,
+            max_instructions: self.max_instructions,
+            max_literal_bytes: self.max_literal_bytes,
+            max_file_size: self.max_file_size,
+        }
+    }
+
+    pub fn settings(&self) -> syncr::Result<Settings> {
+        let config = self.config.as_ref().map(Config::from_file).transpose()?;
+        let modules = match &config {
+            Some(config) => Modules::from_config(config)?,
+            None => Modules::root(&self.root)?,
+        };
+        let config = config.unwrap_or_default();
+
+        let secrets = self
+            .secrets_file
+            .as_ref()
+            .or(config.secrets_file.as_ref())
+            .map(Secrets::from_file)
+            .transpose()?;
+        if let Some(module) = modules.iter().find(|module| !module.config.users.is_empty()) {
+            if secrets.is_none() {
+                return Err(SyncrError::InvalidConfig {
+                    path: module.name.clone(),
+                    reason: "restricting a module to some users requires a secrets file".to_string(),
+                });
+            }
+        }
+
+        let limits = self.limits();
+        limits.validate()?;
+
+        let max_connections = self.max_connections.or(config.max_connections).unwrap_or(64);
+        Ok(Settings {
+            address: self
+                .address
+                .clone()
+                .or(config.address)
+                .unwrap_or_else(|| "0.0.0.0:8000".to_string()),
+            modules,
+            limits,
+            secrets,
+            connections: Arc::new(ConnectionLimit::new(max_connections)),
+            locks: Arc::default(),
+            timeout: Duration::from_secs(self.timeout),
+            #[cfg(feature = "tls")]
+            tls: self.tls_config()?,
+        })
+    }
 
+    #[cfg(feature = "tls")]
+    pub fn tls_config(&self) -> syncr::Result<Option<Arc<syncr::tls::ServerConfig>>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(syncr::tls::server_config(cert, key, self.tls_client_ca.as_deref())?)),
+            _ => Ok(None),
+        }
+    }
+}
+
+
+/// How the daemon was configured, shared by every session.
+#[derive(Debug)]
+pub struct Settings {
+    pub address: String,
+    /// Every file requested by a client is resolved inside of one of these.
+    pub modules: Modules,
+    pub limits: Limits,
+    /// If set, clients must authenticate as one of these users.
+    pub secrets: Option<Secrets>,
+    /// The sessions served at the same time, shared with the settings that
+    /// came before.
+    pub connections: Arc<ConnectionLimit>,
+    /// The files currently being updated.
+    pub locks: Arc<FileLocks>,
+    /// How long a session waits for the next message from its client, so
+    /// that a stalled client doesn't keep its permit and lock forever.
+    pub timeout: Duration,
+    /// If set, TCP connections are served over TLS.
+    #[cfg(feature = "tls")]
+    pub tls: Option<Arc<syncr::tls::ServerConfig>>,
+}
 
-pub async fn handle_stream(
-    stream: TcpStream,
-    peer: SocketAddr,
-    inbound_msg_tx: tokio::sync::mpsc::Sender<Message>,
-    outbound_msg_rx: tokio::sync::mpsc::Receiver<Message>
-) -> syncr::Result<()> {
 
+/// The running daemon: its current settings, and every session it serves.
+#[derive(Debug)]
+pub struct Daemon {
+    cli: Cli,
+    settings: RwLock<Arc<Settings>>,
+    sessions: TaskTracker,
+    /// Cancelled once the sessions still in flight should give up.
+    aborted: CancellationToken,
+}
+
+impl Daemon {
+    pub fn new(cli: Cli, settings: Arc<Settings>) -> Self {
+        Self {
+            cli,
+            settings: RwLock::new(settings),
+            sessions: TaskTracker::new(),
+            aborted: CancellationToken::new(),
+        }
+    }
+
+    /// The settings for a new session.
+    pub fn settings(&self) -> Arc<Settings> {
+        self.settings.read().unwrap().clone()
+    }
+
+    /// Read the configuration again, for the sessions started from now on.
+    pub fn reload(&self) -> syncr::Result<()> {
+        let mut settings = self.cli.settings()?;
+        let current = self.settings();
+
+        if settings.address != current.address {
+            warn!("Still listening on {}: changing the address requires a restart.", current.address);
+            settings.address = current.address.clone();
+        }
+        // Sessions still using the old settings count against the new limits,
+        // and keep taking turns with the new sessions for the same files.
+        settings.modules.share_connections(&current.modules);
+        current.connections.set_max(settings.connections.max());
+        settings.connections = current.connections.clone();
+        settings.locks = current.locks.clone();
+
+        *self.settings.write().unwrap() = Arc::new(settings);
+        Ok(())
+    }
+
+    /// Serve a client in the background, until the session is over or
+    /// aborted.
+    pub fn spawn<F, Fut>(&self, session: F)
+    where
+        F: FnOnce(Arc<Settings>, CancellationToken) -> Fut,
+        Fut: std::future::Future<Output = ()> + Send + 'static,
+    {
+        self.sessions.spawn(session(self.settings(), self.aborted.clone()));
+    }
+
+    /// Reload the configuration on SIGHUP, until we're told to shut down.
+    #[cfg(unix)]
+    pub async fn handle_signals(&self) -> syncr::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let mut hangup = signal(SignalKind::hangup())?;
+        loop {
+            tokio::select! {
+                _ = terminate.recv() => break,
+                _ = interrupt.recv() => break,
+                _ = hangup.recv() => match self.reload() {
+                    Ok(()) => info!("Reloaded the configuration."),
+                    Err(e) => error!("Keeping the current configuration, as reloading it failed: {}", e),
+                },
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    pub async fn handle_signals(&self) -> syncr::Result<()> {
+        tokio::signal::ctrl_c().await?;
+        Ok(())
+    }
+
+    /// Wait for the sessions in flight to be over, aborting the ones that
+    /// are still running after `timeout`.
+    pub async fn shut_down(&self, timeout: Duration) {
+        self.sessions.close();
+        if self.sessions.is_empty() {
+            return;
+        }
+        info!("Waiting up to {:?} for {} session(s) to finish.", timeout, self.sessions.len());
+        if tokio::time::timeout(timeout, self.sessions.wait()).await.is_err() {
+            warn!("Aborting {} session(s).", self.sessions.len());
+            self.aborted.cancel();
+            self.sessions.wait().await;
+        }
+    }
+}
+
+
+#[cfg(unix)]
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("{:?} is not an octal file mode", mode)),
+    }
+}
+
+
+pub async fn handle_stream(stream: TcpStream, peer: SocketAddr, settings: Arc<Settings>, aborted: CancellationToken) -> syncr::Result<()> {
     info!("Accepted connection from syncr client: {}", peer);
-    let connection = Connection::new(stream, inbound_msg_tx);
-    connection.run(outbound_msg_rx).await
+    let (read_half, write_half) = stream.into_split();
+    serve(read_half, write_half, settings, Some(peer.ip()), aborted).await
+}
+
+
+/// Go through a whole session with a single client over any transport.
+///
+/// `peer` is the address of the client, if it's connected over the network.
+/// The session gives up as soon as `aborted` is cancelled.
+pub async fn serve<R, W>(
+    read_half: R,
+    write_half: W,
+    settings: Arc<Settings>,
+    peer: Option<IpAddr>,
+    aborted: CancellationToken,
+) -> syncr::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let (outbound_message_tx, outbound_msg_rx) = tokio::sync::mpsc::channel(100);
+    let (inbound_msg_tx, inbound_msg_rx) = tokio::sync::mpsc::channel(100);
+    let connection = Connection::from_parts(read_half, write_half, inbound_msg_tx, settings.limits);
+
+    let state = ConnectionState::new(settings, peer);
+
+    let single_connection = SingleConnection {
+        outbound_message_tx,
+        inbound_msg_rx,
+        state: Arc::new(Mutex::new(state)),
+        aborted,
+    };
+
+    let (connection_result, session_result) = tokio::join!(connection.run(outbound_msg_rx), single_connection.run());
+    session_result?;
+    connection_result
 }
 
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ConnectionState {
     pub checksum: CheckSum,
-    pub weak_checksums: Vec<u32>,
-    pub file_path: String,
-    pub remote_weak_checksums: Vec<u32>,
-    pub hash_table: HashMap<u16, HashMap<u32, Vec<usize>>>,
-    pub own_data: Vec<u8>,
+    pub file_path: PathBuf,
+    pub own_data: Arc<FileData>,
+    pub session: ReceiverState,
+    pub agreement: Option<Agreement>,
+    pub settings: Arc<Settings>,
+    /// The nonce we challenged the client with, if we require authentication.
+    pub nonce: Vec<u8>,
+    /// The user the client authenticated as.
+    pub user: Option<String>,
+    pub peer: Option<IpAddr>,
+    /// Whether the module the file is in is write-only.
+    pub write_only: bool,
+    /// Our place among the connections to the module the file is in.
+    pub slot: Option<ConnectionSlot>,
+    /// Our place among all the connections to the daemon.
+    pub permit: Option<OwnedSemaphorePermit>,
+    /// Our lock on the file, so no other session updates it meanwhile.
+    pub lock: Option<FileLock>,
+    /// The new version of the file, as rebuilt from the instructions so far.
+    pub patch: Option<delta::Patch>,
+}
+
+impl ConnectionState {
+    pub fn new(settings: Arc<Settings>, peer: Option<IpAddr>) -> Self {
+        Self {
+            checksum: CheckSum::default(),
+            file_path: PathBuf::new(),
+            own_data: Arc::default(),
+            session: ReceiverState::default(),
+            agreement: None,
+            settings,
+            nonce: vec![],
+            user: None,
+            peer,
+            write_only: false,
+            slot: None,
+            permit: None,
+            lock: None,
+            patch: None,
+        }
+    }
 }
 
 
@@ -51,152 +399,474 @@ pub struct SingleConnection {
     pub outbound_message_tx: tokio::sync::mpsc::Sender<Message>,
     pub inbound_msg_rx: tokio::sync::mpsc::Receiver<Message>,
     pub state: Arc<Mutex<ConnectionState>>,
+    pub aborted: CancellationToken,
 }
 
 impl SingleConnection {
 
-    pub fn set_file_path(&mut self, path: String) {
+    pub fn own_hello(&self) -> Hello {
+        let mut hello = Hello::new(None, None);
+        if self.state.lock().unwrap().settings.secrets.is_none() {
+            hello.features.retain(|feature| feature != handshake::AUTHENTICATION);
+        }
+        hello
+    }
+
+    pub fn build_hello_msg(&self) -> Message {
+        Message::Hello(self.own_hello())
+    }
+
+    /// Agree on the parameters of the session with the client.
+    pub fn process_hello(&mut self, hello: Hello) -> syncr::Result<()> {
+        let agreement = handshake::negotiate(&hello, &self.own_hello())?;
+        info!("Agreed on {:?}", agreement);
+
+        self.state.lock().unwrap().agreement = Some(agreement);
+        Ok(())
+    }
+
+    /// Challenge the client to authenticate, if we require it to.
+    pub fn build_challenge_msg(&self) -> syncr::Result<Option<Message>> {
         let mut state = self.state.lock().unwrap();
-        state.file_path = path;
+        if state.settings.secrets.is_none() {
+            return Ok(None);
+        }
+        if !state.agreement.as_ref().is_some_and(|agreement| agreement.supports(handshake::AUTHENTICATION)) {
+            return Err(SyncrError::AuthenticationRequired);
+        }
+        state.nonce = auth::challenge();
+        state.session.expect_authentication();
+        Ok(Some(Message::AuthChallenge(state.nonce.clone())))
     }
-    pub fn set_remote_weak_checksums(&mut self, weak_checksums: Vec<u32>) {
+
+    pub fn authenticate(&mut self, user: String, mac: &[u8]) -> syncr::Result<()> {
         let mut state = self.state.lock().unwrap();
-        state.remote_weak_checksums = weak_checksums;
+        let secrets = state.settings.secrets.as_ref().ok_or(SyncrError::AuthenticationRequired)?;
+        secrets.verify(&user, &state.nonce, mac)?;
+        info!("Authenticated as {}", user);
+        state.user = Some(user);
+        Ok(())
     }
 
-    pub fn compute_our_checksums(&mut self) -> syncr::Result<()> {
+    /// Find the file requested by the client in one of our modules, check
+    /// that the client may update it, and pick the checksum configuration.
+    pub fn open_file(&mut self, requested: &str) -> syncr::Result<ChecksumConfig> {
         let mut state = self.state.lock().unwrap();
-        state.own_data = std::fs::read(&state.file_path)?;
-        state.weak_checksums = state.checksum.weak.checksums_non_overlapping(&state.own_data).collect();
+        let settings = state.settings.clone();
+        let (module, path) = settings.modules.find(requested)?;
 
-        Ok(())
+        state.slot = Some(module.admit(state.user.as_deref(), state.peer)?);
+        state.file_path = module.root.resolve(path)?;
+        state.write_only = module.config.write_only;
+
+        let config = state
+            .agreement
+            .as_ref()
+            .map(|agreement| agreement.config(&module.config.checksum))
+            .unwrap_or(module.config.checksum);
+        config.validate()?;
+        Ok(config)
     }
 
-    pub fn compile_hash_table(&self) {
+    /// Wait for any other session updating the same file to be over.
+    pub async fn lock_file(&mut self) {
+        let (locks, path) = {
+            let state = self.state.lock().unwrap();
+            (state.settings.locks.clone(), state.file_path.clone())
+        };
+        let lock = locks.lock(&path).await;
+        self.state.lock().unwrap().lock = Some(lock);
+    }
 
-        let mut hash_table = HashMap::new();
-        let mut state = self.state.lock().unwrap();
+    /// Compute the signatures of our file, and settle the checksum
+    /// configuration now that we know its size.
+    pub async fn compute_our_checksums(&mut self, config: ChecksumConfig) -> syncr::Result<(ChecksumConfig, Signatures)> {
+        let (path, write_only, max_signatures) = {
+            let state = self.state.lock().unwrap();
+            (state.file_path.clone(), state.write_only, state.settings.limits.max_signatures)
+        };
+        if write_only {
+            // Don't give away anything about the file, so the client sends
+            // all of it.
+            let config = config.for_file_size(0);
+            self.state.lock().unwrap().checksum = CheckSum::with_config(&config);
+            return Ok((config, Signatures::default()));
+        }
 
-        for (offset, &checksum) in state.weak_checksums.iter().enumerate() {
-            let checksum_hash: u16 = weak_hash(checksum);
-
-            hash_table
-            .entry(checksum_hash)
-            .and_modify(|m: &mut HashMap<u32, Vec<usize>>| {
-                m
-                .entry(checksum)
-                .and_modify(|strong_hashes| {
-                    strong_hashes.push(offset * state.checksum.strong.block_size);
-                })
-                .or_insert(vec![offset * state.checksum.strong.block_size]);
-                
-            })
-            .or_insert_with(|| HashMap::from_iter([(checksum, vec![offset * state.checksum.strong.block_size])]));
-        }
-        state.hash_table = hash_table;
-    }
-
-    pub fn build_strong_hash_request(&self) -> syncr::Result<Message> {
-        let mut matches = Vec::new();
-    
-        let state = self.state.lock().unwrap();
-
-        for (byte_offset, weak) in state.remote_weak_checksums.iter().enumerate() {
-            let weak_16_bit_hash = weak_hash(*weak);
-            
-            // First, check the 16-bit hash.
-            if !state.hash_table.contains_key(&weak_16_bit_hash) {
-                continue;
-            }
+        let (own_data, checksum, config, signatures) = blocking(move || {
+            let own_data = match FileData::open(&path) {
+                Ok(data) => data,
+                // A new file: the client sends all of it.
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => FileData::default(),
+                Err(error) => return Err(error.into()),
+            };
+            let config = config.for_file_size(own_data.len());
+            let checksum = CheckSum::with_config(&config);
 
-            // Now, the 16-bit hash matches, so we need to check the 32-bit hash.
-            let rolling_hash_map = state.hash_table.get(&weak_16_bit_hash).unwrap();
-            if !rolling_hash_map.contains_key(&weak) {
-                continue;
+            // The client picks the block size, so make sure it can't make us
+            // compute (and send) an unreasonable number of signatures.
+            let count = own_data.len().div_ceil(checksum.strong.block_size);
+            if count > max_signatures {
+                return Err(SyncrError::TooManyEntries {
+                    kind: "BlockSignatures",
+                    count,
+                    max: max_signatures,
+                });
             }
+            let signatures = Signatures::pack(checksum.checksums_non_overlapping(&own_data), checksum.strong.length);
+            Ok((own_data, checksum, config, signatures))
+        })
+        .await?;
 
-            // // Now, the 32-bit hash matches, so we need to check the 128-bit hash.
-            // Add this to the list of indices we want a strong checksum for.
-            matches.push(byte_offset);
+        let mut state = self.state.lock().unwrap();
+        state.own_data = Arc::new(own_data);
+        state.checksum = checksum;
+        Ok((config, signatures))
+    }
+
+    /// Take the new version of our file out of the state, so it can be
+    /// written to without holding the lock, starting it if need be.
+    fn take_patch(&self) -> impl FnOnce() -> syncr::Result<delta::Patch> + Send + 'static {
+        let mut state = self.state.lock().unwrap();
+        let patch = state.patch.take();
+        let (path, algorithm) = (state.file_path.clone(), state.checksum.strong.algorithm);
+        move || match patch {
+            Some(patch) => Ok(patch),
+            None => delta::Patch::new(path, algorithm),
         }
+    }
 
-        Ok(Message::StrongChecksumRequest(matches))
+    /// Add a batch of instructions to the new version of our file.
+    pub async fn apply_instructions(&self, instructions: Vec<Instruction>) -> syncr::Result<()> {
+        let patch = self.take_patch();
+        let own_data = self.state.lock().unwrap().own_data.clone();
+        let patch = blocking(move || {
+            let mut patch = patch()?;
+            patch.apply(&instructions, &own_data)?;
+            Ok(patch)
+        })
+        .await?;
+        self.state.lock().unwrap().patch = Some(patch);
+        Ok(())
+    }
+
+    /// Replace our file with its new version, provided it matches the
+    /// client's `digest`.
+    pub async fn finish_instructions(&self, digest: u128) -> syncr::Result<()> {
+        // Without any instructions, the client's file is empty.
+        let patch = self.take_patch();
+        let size = blocking(move || {
+            let patch = patch()?;
+            let size = patch.len();
+            patch.finish(digest)?;
+            Ok(size)
+        })
+        .await?;
+
+        let mut state = self.state.lock().unwrap();
+        info!("Rebuilt {} ({} bytes).", state.file_path.display(), size);
+        state.session.applied();
+        Ok(())
+    }
+
+    /// Ask for the whole file if the delta didn't reproduce the sender's
+    /// file, or give up if even the whole file didn't.
+    pub fn handle_verification_failure(&self, error: syncr::SyncrError) -> syncr::Result<Message> {
+        let mut state = self.state.lock().unwrap();
+        let fallback_supported = state
+            .agreement
+            .as_ref()
+            .is_some_and(|agreement| agreement.supports(handshake::WHOLE_FILE_FALLBACK));
+        if !fallback_supported || !state.session.fall_back_to_whole_file() {
+            return Err(error);
+        }
+        warn!("{} Falling back to a whole-file transfer.", error);
+        Ok(Message::WholeFileRequest)
+    }
 
+    /// Count the session against the daemon's maximum number of clients.
+    pub fn take_permit(&mut self) -> syncr::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let connections = &state.settings.connections;
+        let permit = connections.try_acquire().ok_or_else(|| SyncrError::ServerBusy(connections.max()))?;
+        state.permit = Some(permit);
+        Ok(())
     }
 
-    pub fn process_strong_hash_response(&self, strong_checksums: Vec<(usize, u128)>) -> Vec<(usize, usize)> {
-        let state = self.state.lock().unwrap();
-        let mut matches = Vec::new();
-        for (remote_offset, strong) in strong_checksums {
-            for &our_offset in state.hash_table.get(&weak_hash(state.remote_weak_checksums[remote_offset])).unwrap().get(&state.remote_weak_checksums[remote_offset]).unwrap().iter() {
-                let our_strong = strong_hash(&state.own_data[our_offset..our_offset + state.checksum.strong.block_size]);
-                if our_strong == strong {
-                    // Our non-overlapping block matches with some block of the sender.
-                    // So sender may ne able to use a reference to this block
-                    // instead of sending it.
-                    matches.push((our_offset, remote_offset));
+    /// Handle a single message from the client, returning
+    /// `ControlFlow::Break` once the session is over.
+    pub async fn process_message(&mut self, msg: Message) -> syncr::Result<ControlFlow<()>> {
+        self.state.lock().unwrap().session.receive(&msg)?;
+
+        match msg {
+            Message::Hello(hello) => {
+                self.process_hello(hello)?;
+                if let Some(challenge) = self.build_challenge_msg()? {
+                    self.outbound_message_tx.send(challenge).await?;
+                }
+            },
+            Message::AuthResponse { user, mac } => {
+                self.authenticate(user, &mac)?;
+            },
+            Message::FileName(path) => {
+                let config = self.open_file(&path)?;
+                self.lock_file().await;
+                let (config, signatures) = self.compute_our_checksums(config).await?;
+                self.outbound_message_tx.send(Message::BlockSignatures { config, signatures }).await?;
+            },
+            Message::Instructions { instructions } => {
+                self.apply_instructions(instructions).await?;
+            },
+            Message::EndOfInstructions { digest } => {
+                match self.finish_instructions(digest).await {
+                    // The file is in sync, so we're done.
+                    Ok(()) => {
+                        self.outbound_message_tx.send(Message::Done).await?;
+                        return Ok(ControlFlow::Break(()));
+                    },
+                    Err(error @ SyncrError::FileDigestMismatch { .. }) => {
+                        let request = self.handle_verification_failure(error)?;
+                        self.outbound_message_tx.send(request).await?;
+                    },
+                    Err(error) => return Err(error),
                 }
+            },
+            Message::Error { code, message } => {
+                return Err(SyncrError::Remote { code, message });
+            },
+            // Anything else was already rejected by the session state.
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Go through the whole session, from the handshake until our file is
+    /// in sync with the client's (or the connection is closed).
+    async fn process_session(&mut self) -> syncr::Result<()> {
+        self.take_permit()?;
+        self.outbound_message_tx.send(self.build_hello_msg()).await?;
+
+        let timeout = self.state.lock().unwrap().settings.timeout;
+        loop {
+            let msg = match tokio::time::timeout(timeout, self.inbound_msg_rx.recv()).await {
+                Ok(Some(msg)) => msg,
+                Ok(None) => break,
+                Err(_) => return Err(SyncrError::TimedOut(timeout)),
+            };
+            if self.process_message(msg).await?.is_break() {
+                break;
             }
         }
-        matches
+        self.state.lock().unwrap().session.closed()
     }
 
     pub async fn run(mut self) -> syncr::Result<()> {
-        while let Some(msg) = self.inbound_msg_rx.recv().await {
-            match msg {
-                Message::FileName(path) => {
-                    self.set_file_path(path);
-                },
-                Message::WeakChecksums(weak_checksums) => {
-                    self.set_remote_weak_checksums(weak_checksums);
-                    self.compute_our_checksums()?;
-                    self.compile_hash_table();
-                    let strong_hash_request = self.build_strong_hash_request()?;
-                    self.outbound_message_tx.send(strong_hash_request).await?;
-                },
-                Message::StrongChecksums(strong_checksums) => {
-                    let matches = self.process_strong_hash_response(strong_checksums);
-                    self.outbound_message_tx.send(Message::Matches(matches)).await?;
-                },
-                _ => {}
+        let aborted = self.aborted.clone();
+        let result = tokio::select! {
+            result = self.process_session() => result,
+            // A file being rebuilt on a blocking thread is either replaced
+            // as a whole or has its temporary file removed once that's done.
+            _ = aborted.cancelled() => Err(SyncrError::ShuttingDown),
+        };
+        if let Err(error) = result {
+            // Let the client know why we're hanging up on them.
+            if let Some(reply) = error.to_message() {
+                let _ = self.outbound_message_tx.send(reply).await;
             }
+            return Err(error);
         }
         Ok(())
     }
 }
 
-#[tokio::main]
-pub async fn main() -> syncr::Result<()> {
+/// Run `f` on a thread where blocking (on the disk, or to hash a whole file)
+/// doesn't hold up the other sessions.
+async fn blocking<T, F>(f: F) -> syncr::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> syncr::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await.map_err(std::io::Error::from)?
+}
+
+pub fn main() -> syncr::Result<()> {
+    // Changing the environment isn't sound once other threads are running,
+    // so take systemd's variables before starting the runtime.
+    #[cfg(unix)]
+    let listen_fds = syncr::systemd::take_listen_fds();
+    #[cfg(not(unix))]
+    let listen_fds = 0;
+
+    let runtime = tokio::runtime::Builder::new_multi_thread().enable_all().build()?;
+    let result = runtime.block_on(run(listen_fds));
+    // Reading stdin (with `--server`) blocks a thread until the client says
+    // something, which a client we timed out on may never do. Files still
+    // being rebuilt by aborted sessions get a moment to be done.
+    runtime.shutdown_timeout(Duration::from_secs(5));
+    result
+}
+
+/// Serve clients until we're told to shut down, given the number of sockets
+/// passed by systemd.
+async fn run(listen_fds: usize) -> syncr::Result<()> {
+    let cli = Cli::parse();
+    let settings = Arc::new(cli.settings()?);
+
+    if cli.server {
+        // stdout carries the protocol, so keep the logs out of it.
+        tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+        return serve(tokio::io::stdin(), tokio::io::stdout(), settings, None, CancellationToken::new()).await;
+    }
+
     tracing_subscriber::fmt::init();
-    let listener = TcpListener::bind("0.0.0.0:8000").await?;
+    let daemon = Daemon::new(cli.clone(), settings);
+
+    // Stop accepting clients as soon as we're told to shut down.
+    let result = tokio::select! {
+        result = listen(&cli, &daemon, listen_fds) => result,
+        result = daemon.handle_signals() => result,
+    };
+    info!("Shutting down.");
+    daemon.shut_down(Duration::from_secs(cli.shutdown_timeout)).await;
+    result
+}
+
+/// Accept clients on the socket passed by systemd if there is one, or on
+/// whichever socket we're configured to listen on.
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn listen(cli: &Cli, daemon: &Daemon, listen_fds: usize) -> syncr::Result<()> {
+    #[cfg(unix)]
+    match syncr::systemd::listener(listen_fds)? {
+        Some(syncr::systemd::Listener::Tcp(listener)) => {
+            info!("Listening on {} (socket-activated)", listener.local_addr()?);
+            return listen_tcp(TcpListener::from_std(listener)?, daemon).await;
+        },
+        Some(syncr::systemd::Listener::Unix(listener)) => {
+            // Don't silently serve in the clear what was meant to be encrypted.
+            #[cfg(feature = "tls")]
+            if daemon.settings().tls.is_some() {
+                return Err(SyncrError::InvalidConfig {
+                    path: "--tls-cert".to_string(),
+                    reason: "TLS is only supported over TCP, but systemd passed a Unix socket".to_string(),
+                });
+            }
+            info!("Listening on the Unix socket passed by systemd");
+            return listen_unix(tokio::net::UnixListener::from_std(listener)?, daemon).await;
+        },
+        None => {},
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &cli.socket {
+        let listener = bind_unix(path, cli.socket_mode)?;
+        let _socket = SocketFile(path.clone());
+        return listen_unix(listener, daemon).await;
+    }
+
+    let listener = TcpListener::bind(&daemon.settings().address).await?;
+    listen_tcp(listener, daemon).await
+}
+
+/// Serve clients over TCP, encrypted if we're configured to.
+async fn listen_tcp(listener: TcpListener, daemon: &Daemon) -> syncr::Result<()> {
+    #[cfg(feature = "tls")]
+    if let Some(config) = daemon.settings().tls.clone() {
+        return listen_tls(listener, syncr::tls::TlsAcceptor::from(config), daemon).await;
+    }
 
     loop {
         let (stream, peer) = listener.accept().await?;
-        
-        let (outbound_message_tx, outbound_msg_rx) = tokio::sync::mpsc::channel(100);
-        let (inbound_msg_tx, inbound_msg_rx) = tokio::sync::mpsc::channel(100);
-        let handle1 = tokio::spawn(async move {
-            match handle_stream(stream, peer, inbound_msg_tx, outbound_msg_rx).await {
-                Ok(_) => info!("Stream handled successfully."),
+
+        daemon.spawn(|settings, aborted| async move {
+            match handle_stream(stream, peer, settings, aborted).await {
+                Ok(_) => info!("Session with {} ran successfully.", peer),
                 Err(e) => error!("Connection error: {}", e),
             };
         });
+    }
+}
 
-        let state: ConnectionState = ConnectionState::default();
-        
-        let single_connection = SingleConnection {
-            outbound_message_tx,
-            inbound_msg_rx,
-            state: Arc::new(Mutex::new(state)),
-        };
+/// Remove the Unix domain socket at `path`, but never anything that isn't a
+/// socket.
+#[cfg(unix)]
+fn remove_socket(path: &std::path::Path) -> syncr::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if std::fs::symlink_metadata(path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// A Unix domain socket we bound, removed once we stop listening on it.
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = remove_socket(&self.0) {
+            warn!("Failed to remove {}: {}", self.0.display(), e);
+        }
+    }
+}
+
+/// Listen on the Unix domain socket at `path`.
+#[cfg(unix)]
+pub fn bind_unix(path: &std::path::Path, mode: u32) -> syncr::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Clean up after a previous run.
+    remove_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    info!("Listening on {}", path.display());
+    Ok(listener)
+}
 
-        let handle2 = tokio::spawn(async move {
-            match single_connection.run().await {
-                Ok(_) => info!("Single connection ran successfully."),
+/// Serve clients on a Unix domain socket.
+#[cfg(unix)]
+pub async fn listen_unix(listener: tokio::net::UnixListener, daemon: &Daemon) -> syncr::Result<()> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+
+        daemon.spawn(|settings, aborted| async move {
+            info!("Accepted connection from syncr client on the Unix socket.");
+            let (read_half, write_half) = stream.into_split();
+            match serve(read_half, write_half, settings, None, aborted).await {
+                Ok(_) => info!("Session ran successfully."),
                 Err(e) => error!("Connection error: {}", e),
             };
         });
+    }
+}
+
 
-        let _ = tokio::join!(handle1, handle2);
+/// Serve clients over TLS.
+#[cfg(feature = "tls")]
+pub async fn listen_tls(listener: TcpListener, acceptor: syncr::tls::TlsAcceptor, daemon: &Daemon) -> syncr::Result<()> {
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+
+        daemon.spawn(|settings, aborted| async move {
+            info!("Accepted connection from syncr client: {}", peer);
+            let handshake = tokio::select! {
+                handshake = tokio::time::timeout(settings.timeout, acceptor.accept(stream)) => handshake,
+                _ = aborted.cancelled() => return,
+            };
+            let result = match handshake {
+                Ok(Ok(stream)) => {
+                    let (read_half, write_half) = tokio::io::split(stream);
+                    serve(read_half, write_half, settings, Some(peer.ip()), aborted).await
+                },
+                Ok(Err(e)) => Err(e.into()),
+                Err(_) => Err(SyncrError::TimedOut(settings.timeout)),
+            };
+            match result {
+                Ok(_) => info!("Session with {} ran successfully.", peer),
+                Err(e) => error!("Connection error: {}", e),
+            };
+        });
     }
-}
\ No newline at end of file
+}
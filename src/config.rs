@@ -0,0 +1,438 @@
+//! The `syncrd` configuration file, and the modules it serves.
+//!
+//! Like rsyncd.conf, the configuration file describes named modules, each
+//! serving a directory to some set of clients:
+//!
+//! ```toml
+//! address = "0.0.0.0:8000"
+//! max_connections = 64
+//! secrets_file = "/etc/syncrd.secrets"
+//!
+//! [modules.backups]
+//! path = "/srv/backups"
+//! users = ["alice"]
+//! hosts = ["10.0.0.0/8", "::1/128"]
+//! max_connections = 4
+//! checksum = { block_size = 4096, strong_hash = "sha256" }
+//! ```
+//!
+//! Clients then name files as `module/path`, e.g. `backups/db.sql`.
+//!
+//! With a secrets file, every client has to authenticate before naming a
+//! file; a module's `users` further restricts who may use it.
+
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use ipnet::IpNet;
+use serde::Deserialize;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{sandbox::Root, ChecksumConfig, SyncrError};
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The address to listen on for TCP connections.
+    pub address: Option<String>,
+    /// The `user:secret` pairs clients authenticate with (see `syncr::auth`).
+    pub secrets_file: Option<PathBuf>,
+    /// The maximum number of sessions served at the same time, across all
+    /// modules.
+    pub max_connections: Option<usize>,
+    #[serde(default)]
+    pub modules: BTreeMap<String, ModuleConfig>,
+}
+
+impl Config {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let path = path.as_ref();
+        Self::parse(&std::fs::read_to_string(path)?).map_err(|reason| SyncrError::InvalidConfig {
+            path: path.display().to_string(),
+            reason,
+        })
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        toml::from_str(contents).map_err(|e| e.to_string())
+    }
+}
+
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModuleConfig {
+    /// The directory served by the module.
+    pub path: PathBuf,
+    /// Turn every client away, e.g. while the directory is being maintained.
+    /// (`syncrd` only ever receives files, so there's no read-only module.)
+    #[serde(default)]
+    pub disabled: bool,
+    /// Clients may update files, but not learn anything about their current
+    /// contents: no block signatures are sent, so whole files are transferred.
+    #[serde(default)]
+    pub write_only: bool,
+    /// If not empty, only these (authenticated) users may use the module.
+    #[serde(default)]
+    pub users: Vec<String>,
+    /// If not empty, only clients connecting from these networks may use the
+    /// module.
+    #[serde(default)]
+    pub hosts: Vec<IpNet>,
+    /// The maximum number of sessions using the module at the same time.
+    pub max_connections: Option<usize>,
+    /// The checksum configuration to use, unless the client asks for another
+    /// one. The strong checksum length is derived from each file's size
+    /// unless given.
+    #[serde(default)]
+    pub checksum: ChecksumConfig,
+}
+
+
+/// A directory served by the daemon, and who may access it.
+#[derive(Debug)]
+pub struct Module {
+    pub name: String,
+    pub root: Root,
+    pub config: ModuleConfig,
+    // The number of sessions currently using the module.
+    connections: Arc<AtomicUsize>,
+}
+
+impl Module {
+    pub fn new(name: String, config: ModuleConfig) -> crate::Result<Self> {
+        config.checksum.validate()?;
+        Ok(Self {
+            name,
+            root: Root::new(&config.path)?,
+            config,
+            connections: Arc::new(AtomicUsize::new(0)),
+        })
+    }
+
+    /// Check that a client may update files in the module, and count it
+    /// against the module's connections until the returned slot is dropped.
+    pub fn admit(&self, user: Option<&str>, peer: Option<IpAddr>) -> crate::Result<ConnectionSlot> {
+        let denied = |reason| SyncrError::AccessDenied {
+            module: self.name.clone(),
+            reason,
+        };
+
+        if self.config.disabled {
+            return Err(denied("the module is disabled"));
+        }
+        if !self.config.users.is_empty() && !user.is_some_and(|user| self.config.users.iter().any(|u| u == user)) {
+            return Err(denied("the user is not allowed"));
+        }
+        let peer = peer.map(|peer| peer.to_canonical());
+        if !self.config.hosts.is_empty() && !peer.is_some_and(|peer| self.config.hosts.iter().any(|net| net.contains(&peer))) {
+            return Err(denied("the host is not allowed"));
+        }
+
+        let count = self.connections.fetch_add(1, Ordering::SeqCst);
+        let slot = ConnectionSlot(self.connections.clone());
+        if self.config.max_connections.is_some_and(|max| count >= max) {
+            return Err(SyncrError::TooManyConnections(self.name.clone()));
+        }
+        Ok(slot)
+    }
+}
+
+
+/// A session's place among a module's connections, given up on drop.
+#[derive(Debug)]
+pub struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+
+/// The maximum number of sessions served at the same time, across all
+/// modules, which may change (e.g. when reloading the configuration) while
+/// sessions are in flight.
+#[derive(Debug)]
+pub struct ConnectionLimit {
+    /// A permit for every session that may be served at the same time.
+    permits: Arc<Semaphore>,
+    limit: Mutex<Limit>,
+}
+
+#[derive(Debug)]
+struct Limit {
+    max: usize,
+    /// The permits to forget as soon as the sessions holding them are over,
+    /// since the maximum went down.
+    excess: usize,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(max)),
+            limit: Mutex::new(Limit { max, excess: 0 }),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.limit.lock().unwrap().max
+    }
+
+    /// Count a new session until the returned permit is dropped, unless the
+    /// maximum number of sessions are in flight already.
+    pub fn try_acquire(&self) -> Option<OwnedSemaphorePermit> {
+        let mut limit = self.limit.lock().unwrap();
+        limit.excess -= self.permits.forget_permits(limit.excess);
+        self.permits.clone().try_acquire_owned().ok()
+    }
+
+    /// Change the maximum number of sessions. The sessions in flight count
+    /// against the new maximum.
+    pub fn set_max(&self, max: usize) {
+        let mut limit = self.limit.lock().unwrap();
+        if max >= limit.max {
+            let added = max - limit.max;
+            let restored = added.min(limit.excess);
+            limit.excess -= restored;
+            self.permits.add_permits(added - restored);
+        } else {
+            let removed = limit.max - max;
+            limit.excess += removed - self.permits.forget_permits(removed);
+        }
+        limit.max = max;
+    }
+}
+
+
+/// Everything the daemon serves.
+#[derive(Debug)]
+pub enum Modules {
+    /// A single directory, with files named relative to it.
+    Root(Module),
+    /// Named modules, with files named `module/path`.
+    Named(BTreeMap<String, Module>),
+}
+
+impl Modules {
+    /// Serve a single directory, without any access restrictions.
+    pub fn root<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        let config = ModuleConfig {
+            path: path.as_ref().to_path_buf(),
+            ..Default::default()
+        };
+        Ok(Modules::Root(Module::new(path.as_ref().display().to_string(), config)?))
+    }
+
+    pub fn from_config(config: &Config) -> crate::Result<Self> {
+        let modules = config
+            .modules
+            .iter()
+            .map(|(name, module)| Ok((name.clone(), Module::new(name.clone(), module.clone())?)))
+            .collect::<crate::Result<_>>()?;
+        Ok(Modules::Named(modules))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Module> {
+        let (root, named) = match self {
+            Modules::Root(module) => (Some(module), None),
+            Modules::Named(modules) => (None, Some(modules.values())),
+        };
+        root.into_iter().chain(named.into_iter().flatten())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Module> {
+        let (root, named) = match self {
+            Modules::Root(module) => (Some(module), None),
+            Modules::Named(modules) => (None, Some(modules.values_mut())),
+        };
+        root.into_iter().chain(named.into_iter().flatten())
+    }
+
+    /// Keep counting the sessions of the `previous` modules of the same name
+    /// (e.g. when reloading the configuration), so the sessions still using
+    /// them count against the new limits.
+    pub fn share_connections(&mut self, previous: &Modules) {
+        for module in self.iter_mut() {
+            if let Some(old) = previous.iter().find(|old| old.name == module.name) {
+                module.connections = old.connections.clone();
+            }
+        }
+    }
+
+    /// Find the module a requested file is in, and the path of the file
+    /// relative to the module.
+    pub fn find<'a>(&self, requested: &'a str) -> crate::Result<(&Module, &'a str)> {
+        match self {
+            Modules::Root(module) => Ok((module, requested)),
+            Modules::Named(modules) => {
+                let (name, path) = requested.split_once('/').unwrap_or((requested, ""));
+                let module = modules.get(name).ok_or_else(|| SyncrError::UnknownModule(name.to_string()))?;
+                Ok((module, path))
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("syncr-config-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    #[test]
+    fn configs_are_parsed() {
+        let config = Config::parse(
+            r#"
+            address = "127.0.0.1:9000"
+
+            [modules.backups]
+            path = "/srv/backups"
+            write_only = true
+            users = ["alice"]
+            hosts = ["10.0.0.0/8"]
+            max_connections = 2
+            checksum = { block_size = 4096 }
+
+            [modules.public]
+            path = "/srv/public"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.address.as_deref(), Some("127.0.0.1:9000"));
+        let backups = &config.modules["backups"];
+        assert!(backups.write_only && !backups.disabled);
+        assert_eq!(backups.hosts, vec!["10.0.0.0/8".parse::<IpNet>().unwrap()]);
+        assert_eq!(backups.checksum, ChecksumConfig { block_size: 4096, ..Default::default() });
+        assert_eq!(config.modules["public"].max_connections, None);
+
+        assert!(Config::parse("[modules.a]\npath = \"/\"\nreadonly = true\n").is_err());
+        assert!(Config::parse("[modules.a]\npath = \"/\"\nread_only = true\n").is_err());
+        assert!(Config::parse("[modules.a]\npath = \"/\"\nhosts = [\"nonsense\"]\n").is_err());
+    }
+
+    #[test]
+    fn files_are_found_in_their_module() {
+        let directory = directory("find");
+        let config = Config {
+            modules: BTreeMap::from([("data".to_string(), ModuleConfig { path: directory.clone(), ..Default::default() })]),
+            ..Default::default()
+        };
+        let modules = Modules::from_config(&config).unwrap();
+
+        let (module, path) = modules.find("data/sub/file.txt").unwrap();
+        assert_eq!((module.name.as_str(), path), ("data", "sub/file.txt"));
+        assert!(matches!(modules.find("other/file.txt"), Err(SyncrError::UnknownModule(name)) if name == "other"));
+
+        let root = Modules::root(&directory).unwrap();
+        assert_eq!(root.find("data/file.txt").unwrap().1, "data/file.txt");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn modules_restrict_access() {
+        let directory = directory("admit");
+        let module = |config: ModuleConfig| {
+            Module::new("module".to_string(), ModuleConfig { path: directory.clone(), ..config }).unwrap()
+        };
+        let localhost = Some(IpAddr::from([127, 0, 0, 1]));
+
+        let disabled = module(ModuleConfig { disabled: true, ..Default::default() });
+        assert!(matches!(
+            disabled.admit(None, localhost),
+            Err(SyncrError::AccessDenied { reason: "the module is disabled", .. })
+        ));
+
+        let users = module(ModuleConfig { users: vec!["alice".to_string()], ..Default::default() });
+        assert!(users.admit(Some("alice"), None).is_ok());
+        assert!(matches!(users.admit(Some("bob"), None), Err(SyncrError::AccessDenied { .. })));
+        assert!(matches!(users.admit(None, None), Err(SyncrError::AccessDenied { .. })));
+
+        let hosts = module(ModuleConfig { hosts: vec!["127.0.0.0/8".parse().unwrap()], ..Default::default() });
+        assert!(hosts.admit(None, localhost).is_ok());
+        assert!(hosts.admit(None, Some("::ffff:127.0.0.1".parse().unwrap())).is_ok());
+        assert!(matches!(hosts.admit(None, Some(IpAddr::from([10, 0, 0, 1]))), Err(SyncrError::AccessDenied { .. })));
+        assert!(matches!(hosts.admit(None, None), Err(SyncrError::AccessDenied { .. })));
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn connections_are_counted_until_the_session_ends() {
+        let directory = directory("connections");
+        let module = Module::new(
+            "module".to_string(),
+            ModuleConfig { path: directory.clone(), max_connections: Some(1), ..Default::default() },
+        )
+        .unwrap();
+
+        let slot = module.admit(None, None).unwrap();
+        assert!(matches!(module.admit(None, None), Err(SyncrError::TooManyConnections(_))));
+        drop(slot);
+        assert!(module.admit(None, None).is_ok());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn reloaded_modules_keep_counting_connections() {
+        let directory = directory("reload");
+        let config = |max_connections| Config {
+            modules: BTreeMap::from([(
+                "data".to_string(),
+                ModuleConfig { path: directory.clone(), max_connections: Some(max_connections), ..Default::default() },
+            )]),
+            ..Default::default()
+        };
+        let previous = Modules::from_config(&config(2)).unwrap();
+        let slot = previous.find("data/file").unwrap().0.admit(None, None).unwrap();
+
+        let mut reloaded = Modules::from_config(&config(1)).unwrap();
+        reloaded.share_connections(&previous);
+        let module = reloaded.find("data/file").unwrap().0;
+        assert!(matches!(module.admit(None, None), Err(SyncrError::TooManyConnections(_))));
+        drop(slot);
+        assert!(module.admit(None, None).is_ok());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn connection_limits_change_with_sessions_in_flight() {
+        let limit = ConnectionLimit::new(3);
+        let sessions: Vec<_> = (0..3).map(|_| limit.try_acquire().unwrap()).collect();
+
+        // The sessions in flight count against the new maximum...
+        limit.set_max(1);
+        assert_eq!(limit.max(), 1);
+        let mut sessions = sessions.into_iter();
+        drop(sessions.next());
+        assert!(limit.try_acquire().is_none());
+        drop(sessions.next());
+        assert!(limit.try_acquire().is_none());
+        drop(sessions.next());
+        let session = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        // ...and going back up doesn't count the forgotten permits twice.
+        limit.set_max(2);
+        let other = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+        limit.set_max(0);
+        limit.set_max(2);
+        assert!(limit.try_acquire().is_none());
+        drop((session, other));
+        let sessions: Vec<_> = (0..2).map(|_| limit.try_acquire().unwrap()).collect();
+        assert!(limit.try_acquire().is_none());
+        drop(sessions);
+    }
+}
@@ -0,0 +1,575 @@
+//! Turns the matching blocks reported by the receiver into the list of
+//! instructions it needs to rebuild the sender's file, and applies those
+//! instructions on the receiving end.
+//!
+//! The instructions are streamed in batches of at most [`BATCH_INSTRUCTIONS`]
+//! instructions and [`BATCH_LITERAL_BYTES`] literal bytes, so a whole file
+//! never has to fit in a single frame (or in the receiver's memory).
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::network::Instruction;
+use crate::strong_checksum::{Digester, StrongHash, StrongHashAlgorithm};
+use crate::SyncrError;
+
+
+/// The most literal bytes the sender puts in a batch of instructions.
+pub const BATCH_LITERAL_BYTES: usize = 1024 * 1024;
+
+/// The most instructions the sender puts in a batch.
+pub const BATCH_INSTRUCTIONS: usize = 4096;
+
+
+/// Given the `(receiver_offset, sender_offset)` pairs of blocks that the
+/// receiver already has, issue an ordered list of instructions that covers
+/// the whole of `data` (the sender's file).
+///
+/// Matched regions become [`Instruction::Replicate`] and every gap in between
+/// becomes [`Instruction::NewData`] carrying the literal bytes. Matches that
+/// overlap an already covered region are skipped, and runs of adjacent matches
+/// are coalesced into a single [`Instruction::Replicate`].
+pub fn instructions_from_matches(matches: &[(usize, usize)], data: &[u8], block_size: usize) -> Vec<Instruction> {
+    let mut matches = matches.to_vec();
+    matches.sort_unstable_by_key(|&(receiver_offset, sender_offset)| (sender_offset, receiver_offset));
+
+    let mut instructions = Vec::new();
+    // The first byte of our file that isn't covered by an instruction yet.
+    let mut cursor = 0;
+
+    for (receiver_offset, sender_offset) in matches {
+        if sender_offset < cursor || sender_offset >= data.len() {
+            continue;
+        }
+        let length = block_size.min(data.len() - sender_offset);
+
+        if sender_offset > cursor {
+            instructions.push(new_data(cursor, &data[cursor..sender_offset]));
+        }
+
+        match instructions.last_mut() {
+            Some(Instruction::Replicate { from_offset, length: run_length, new_offset })
+                if *from_offset + *run_length == receiver_offset
+                && *new_offset + *run_length == sender_offset => {
+                *run_length += length;
+            },
+            _ => {
+                instructions.push(Instruction::Replicate {
+                    from_offset: receiver_offset,
+                    length,
+                    new_offset: sender_offset,
+                });
+            }
+        }
+        cursor = sender_offset + length;
+    }
+
+    if cursor < data.len() {
+        instructions.push(new_data(cursor, &data[cursor..]));
+    }
+
+    instructions
+}
+
+/// Split `instructions` into batches of at most [`BATCH_INSTRUCTIONS`]
+/// instructions and [`BATCH_LITERAL_BYTES`] literal bytes, breaking up larger
+/// [`Instruction::NewData`].
+pub fn batch_instructions(instructions: Vec<Instruction>) -> Vec<Vec<Instruction>> {
+    let mut batches = Vec::new();
+    let mut batch = Vec::new();
+    let mut literal_bytes = 0;
+
+    for instruction in instructions.into_iter().flat_map(split_new_data) {
+        let length = match &instruction {
+            Instruction::NewData { bytes, .. } => bytes.len(),
+            Instruction::Replicate { .. } => 0,
+        };
+        if batch.len() == BATCH_INSTRUCTIONS || literal_bytes + length > BATCH_LITERAL_BYTES {
+            batches.push(std::mem::take(&mut batch));
+            literal_bytes = 0;
+        }
+        literal_bytes += length;
+        batch.push(instruction);
+    }
+
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+fn split_new_data(instruction: Instruction) -> Vec<Instruction> {
+    match instruction {
+        Instruction::NewData { offset, bytes, .. } if bytes.len() > BATCH_LITERAL_BYTES => {
+            bytes
+                .chunks(BATCH_LITERAL_BYTES)
+                .enumerate()
+                .map(|(index, chunk)| new_data(offset + index * BATCH_LITERAL_BYTES, chunk))
+                .collect()
+        },
+        instruction => vec![instruction],
+    }
+}
+
+fn new_data(offset: usize, bytes: &[u8]) -> Instruction {
+    Instruction::NewData {
+        offset,
+        length: bytes.len(),
+        bytes: bytes.to_vec(),
+    }
+}
+
+/// Check that `instructions` describe a file that can be rebuilt from a
+/// file of `available` bytes, without gaps or overlaps.
+pub fn validate_instructions(instructions: &[Instruction], available: usize) -> crate::Result<()> {
+    validate_from(instructions, available, 0).map(|_| ())
+}
+
+/// Like [`validate_instructions`], for instructions that start at offset
+/// `expected` of the new file. Returns the offset they end at.
+fn validate_from(instructions: &[Instruction], available: usize, mut expected: usize) -> crate::Result<usize> {
+    for instruction in instructions {
+        let (offset, length) = match instruction {
+            Instruction::NewData { offset, length, bytes } => {
+                if *length != bytes.len() {
+                    return Err(SyncrError::NewDataLengthMismatch {
+                        offset: *offset,
+                        length: *length,
+                        actual: bytes.len(),
+                    });
+                }
+                (*offset, *length)
+            },
+            Instruction::Replicate { from_offset, length, new_offset } => {
+                if from_offset.checked_add(*length).is_none_or(|end| end > available) {
+                    return Err(SyncrError::ReplicateOutOfBounds {
+                        from_offset: *from_offset,
+                        length: *length,
+                        available,
+                    });
+                }
+                (*new_offset, *length)
+            }
+        };
+        if offset != expected {
+            return Err(SyncrError::InstructionOutOfOrder { offset, expected });
+        }
+        expected = offset.saturating_add(length);
+    }
+    Ok(expected)
+}
+
+/// Write the new version of the file described by `instructions` to `writer`.
+///
+/// [`Instruction::Replicate`] copies a region of `own_data` (the receiver's
+/// current version of the file) and [`Instruction::NewData`] writes the
+/// literal bytes sent by the sender.
+///
+/// The instructions are validated with [`validate_instructions`] before
+/// anything is written.
+pub fn apply_instructions<W: Write>(instructions: &[Instruction], own_data: &[u8], writer: &mut W) -> crate::Result<()> {
+    validate_instructions(instructions, own_data.len())?;
+    write_instructions(instructions, own_data, writer)
+}
+
+fn write_instructions<W: Write>(instructions: &[Instruction], own_data: &[u8], writer: &mut W) -> crate::Result<()> {
+    for instruction in instructions {
+        match instruction {
+            Instruction::NewData { bytes, .. } => {
+                writer.write_all(bytes)?;
+            },
+            Instruction::Replicate { from_offset, length, .. } => {
+                writer.write_all(&own_data[*from_offset..*from_offset + *length])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Rebuild the file at `path` from `instructions`.
+///
+/// The new version must hash to `digest` (the `algorithm` hash of the sender's
+/// whole file), otherwise [`SyncrError::FileDigestMismatch`] is returned and `path`
+/// is left untouched. See [`Patch`] for instructions that arrive in batches.
+pub fn patch_file<P: AsRef<Path>>(
+    path: P,
+    instructions: &[Instruction],
+    own_data: &[u8],
+    algorithm: StrongHashAlgorithm,
+    digest: u128,
+) -> crate::Result<()> {
+    let mut patch = Patch::new(path, algorithm)?;
+    patch.apply(instructions, own_data)?;
+    patch.finish(digest)
+}
+
+
+/// The new version of a file, rebuilt from batches of instructions as they
+/// arrive.
+///
+/// It is written to a temporary file in the same directory and hashed as it
+/// goes. Only once it matches the sender's digest is it given the permissions
+/// of the current file, fsync'd, and then atomically renamed over the file, so
+/// a crash mid-sync never leaves a half-written file behind. The temporary
+/// file is removed if the patch is dropped before that.
+pub struct Patch {
+    path: PathBuf,
+    temp: TempFile,
+    writer: HashingWriter<BufWriter<File>>,
+    /// The offset in the new file the next instruction starts at.
+    offset: usize,
+}
+
+impl std::fmt::Debug for Patch {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter
+            .debug_struct("Patch")
+            .field("path", &self.path)
+            .field("temp", &self.temp)
+            .field("offset", &self.offset)
+            .finish()
+    }
+}
+
+impl Patch {
+    /// Start rebuilding the file at `path`, hashing it with `algorithm`.
+    pub fn new<P: AsRef<Path>>(path: P, algorithm: StrongHashAlgorithm) -> crate::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let (temp, file) = TempFile::new_beside(&path)?;
+        Ok(Self {
+            path,
+            temp,
+            writer: HashingWriter {
+                inner: BufWriter::new(file),
+                digester: algorithm.digester(),
+            },
+            offset: 0,
+        })
+    }
+
+    /// Write the next batch of `instructions`, which must pick up where the
+    /// previous one ended.
+    ///
+    /// [`Instruction::Replicate`] copies a region of `own_data` (the
+    /// receiver's current version of the file) and [`Instruction::NewData`]
+    /// writes the literal bytes sent by the sender. The batch is validated
+    /// before anything is written.
+    pub fn apply(&mut self, instructions: &[Instruction], own_data: &[u8]) -> crate::Result<()> {
+        let end = validate_from(instructions, own_data.len(), self.offset)?;
+        write_instructions(instructions, own_data, &mut self.writer)?;
+        self.offset = end;
+        Ok(())
+    }
+
+    /// The size of the new version so far.
+    pub fn len(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset == 0
+    }
+
+    /// Replace the file with its new version, provided it hashes to `digest`.
+    pub fn finish(self, digest: u128) -> crate::Result<()> {
+        let Self { path, temp, writer, .. } = self;
+
+        // The temporary file is removed on drop if it doesn't match.
+        let actual = writer.digester.finish();
+        if actual != digest {
+            return Err(SyncrError::FileDigestMismatch { expected: digest, actual });
+        }
+
+        let file = writer.inner.into_inner().map_err(|e| e.into_error())?;
+        match std::fs::metadata(&path) {
+            Ok(metadata) => file.set_permissions(metadata.permissions())?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {},
+            Err(error) => return Err(error.into()),
+        }
+        file.sync_all()?;
+        drop(file);
+
+        temp.persist(&path)
+    }
+}
+
+
+/// Feeds everything written to `inner` to `digester` as well.
+struct HashingWriter<W> {
+    inner: W,
+    digester: Box<dyn Digester>,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.digester.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+
+/// A temporary file that is removed on drop unless it is persisted.
+#[derive(Debug)]
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Create a new, empty temporary file in the same directory as `path`,
+    /// opened for writing.
+    fn new_beside(path: &Path) -> crate::Result<(Self, File)> {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp_path = directory.join(format!(
+            ".{}.{}.{}.syncr-tmp",
+            file_name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let file = OpenOptions::new().write(true).create_new(true).open(&temp_path)?;
+
+        let temp = Self {
+            path: temp_path,
+            persisted: false,
+        };
+        Ok((temp, file))
+    }
+
+    /// Atomically move the temporary file over `path`.
+    fn persist(mut self, path: &Path) -> crate::Result<()> {
+        std::fs::rename(&self.path, path)?;
+        self.persisted = true;
+
+        // Make sure the rename itself is durable.
+        #[cfg(unix)]
+        if let Some(directory) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            File::open(directory)?.sync_all()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Rebuild the sender's file from the receiver's data and the instructions.
+    fn reconstruct(instructions: &[Instruction], receiver_data: &[u8]) -> Vec<u8> {
+        let mut result = Vec::new();
+        apply_instructions(instructions, receiver_data, &mut result).unwrap();
+        result
+    }
+
+    /// Find every non-overlapping block of the receiver at every offset of the sender.
+    fn naive_matches(receiver_data: &[u8], sender_data: &[u8], block_size: usize) -> Vec<(usize, usize)> {
+        let mut matches = Vec::new();
+        for receiver_offset in (0..receiver_data.len()).step_by(block_size) {
+            if receiver_offset + block_size > receiver_data.len() {
+                break;
+            }
+            let block = &receiver_data[receiver_offset..receiver_offset + block_size];
+            for sender_offset in 0..sender_data.len() {
+                if sender_data[sender_offset..].starts_with(block) {
+                    matches.push((receiver_offset, sender_offset));
+                }
+            }
+        }
+        matches
+    }
+
+    #[test]
+    fn no_matches_sends_everything() {
+        let data = b"hello world";
+        let instructions = instructions_from_matches(&[], data, 4);
+        assert_eq!(instructions.len(), 1);
+        assert_eq!(reconstruct(&instructions, b""), data);
+    }
+
+    #[test]
+    fn empty_file_has_no_instructions() {
+        assert!(instructions_from_matches(&[(0, 0)], b"", 4).is_empty());
+    }
+
+    #[test]
+    fn adjacent_matches_are_coalesced() {
+        let receiver_data = b"aaaabbbbcccc";
+        let sender_data = b"xaaaabbbbccccy";
+        let instructions = instructions_from_matches(&[(0, 1), (4, 5), (8, 9)], sender_data, 4);
+        assert_eq!(instructions.len(), 3);
+        assert!(matches!(instructions[1], Instruction::Replicate { from_offset: 0, length: 12, new_offset: 1 }));
+        assert_eq!(reconstruct(&instructions, receiver_data), sender_data);
+    }
+
+    #[test]
+    fn replicate_past_the_end_is_an_error() {
+        let instructions = [Instruction::Replicate { from_offset: 2, length: 4, new_offset: 0 }];
+        let result = apply_instructions(&instructions, b"abc", &mut Vec::new());
+        assert!(matches!(result, Err(SyncrError::ReplicateOutOfBounds { available: 3, .. })));
+    }
+
+    #[test]
+    fn replicate_overflowing_offset_is_an_error() {
+        let instructions = [Instruction::Replicate { from_offset: usize::MAX, length: 2, new_offset: 0 }];
+        let result = apply_instructions(&instructions, b"abc", &mut Vec::new());
+        assert!(matches!(result, Err(SyncrError::ReplicateOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn new_data_with_wrong_length_is_an_error() {
+        let instructions = [Instruction::NewData { offset: 0, length: 5, bytes: vec![1, 2] }];
+        let result = validate_instructions(&instructions, 0);
+        assert!(matches!(result, Err(SyncrError::NewDataLengthMismatch { length: 5, actual: 2, .. })));
+    }
+
+    #[test]
+    fn instructions_with_gaps_are_an_error() {
+        let instructions = [
+            Instruction::NewData { offset: 0, length: 2, bytes: vec![1, 2] },
+            Instruction::Replicate { from_offset: 0, length: 2, new_offset: 3 },
+        ];
+        let result = validate_instructions(&instructions, 2);
+        assert!(matches!(result, Err(SyncrError::InstructionOutOfOrder { offset: 3, expected: 2 })));
+    }
+
+    #[test]
+    fn patch_file_replaces_the_file() {
+        let directory = std::env::temp_dir().join(format!("syncr-delta-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("file.txt");
+        std::fs::write(&path, b"aaaabbbb").unwrap();
+
+        let sender_data = b"bbbbccaaaa";
+        let algorithm = StrongHashAlgorithm::default();
+        let instructions = instructions_from_matches(&[(4, 0), (0, 6)], sender_data, 4);
+        patch_file(&path, &instructions, b"aaaabbbb", algorithm, algorithm.digest(sender_data)).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), sender_data);
+        // Only the patched file is left behind.
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn patch_file_keeps_the_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let directory = std::env::temp_dir().join(format!("syncr-delta-mode-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("file.txt");
+        std::fs::write(&path, b"aaaabbbb").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+
+        let instructions = instructions_from_matches(&[(0, 0)], b"aaaacc", 4);
+        let algorithm = StrongHashAlgorithm::default();
+        patch_file(&path, &instructions, b"aaaabbbb", algorithm, algorithm.digest(b"aaaacc")).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), b"aaaacc");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn patch_file_with_wrong_digest_keeps_the_file() {
+        let directory = std::env::temp_dir().join(format!("syncr-delta-digest-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("file.txt");
+        std::fs::write(&path, b"aaaabbbb").unwrap();
+
+        let instructions = instructions_from_matches(&[], b"cccc", 4);
+        let algorithm = StrongHashAlgorithm::default();
+        let result = patch_file(&path, &instructions, b"aaaabbbb", algorithm, algorithm.digest(b"dddd"));
+
+        assert!(matches!(result, Err(SyncrError::FileDigestMismatch { .. })));
+        assert_eq!(std::fs::read(&path).unwrap(), b"aaaabbbb");
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn batches_split_large_new_data() {
+        let data = vec![7; 2 * BATCH_LITERAL_BYTES + 1];
+        let mut instructions = vec![Instruction::Replicate { from_offset: 0, length: 4, new_offset: 0 }];
+        instructions.extend(instructions_from_matches(&[], &data, 4).into_iter().map(|instruction| match instruction {
+            Instruction::NewData { offset, length, bytes } => Instruction::NewData { offset: offset + 4, length, bytes },
+            instruction => instruction,
+        }));
+
+        let batches = batch_instructions(instructions);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [2, 1, 1]);
+        let instructions: Vec<_> = batches.into_iter().flatten().collect();
+        validate_instructions(&instructions, 4).unwrap();
+        assert_eq!(reconstruct(&instructions, b"abcd")[4..], data[..]);
+    }
+
+    #[test]
+    fn batches_hold_a_bounded_number_of_instructions() {
+        let instructions = (0..BATCH_INSTRUCTIONS + 1)
+            .map(|offset| Instruction::Replicate { from_offset: 0, length: 1, new_offset: offset })
+            .collect();
+        let batches = batch_instructions(instructions);
+        assert_eq!(batches.iter().map(Vec::len).collect::<Vec<_>>(), [BATCH_INSTRUCTIONS, 1]);
+        assert!(batch_instructions(vec![]).is_empty());
+    }
+
+    #[test]
+    fn patch_applies_instructions_in_batches() {
+        let directory = std::env::temp_dir().join(format!("syncr-delta-batch-test-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("file.txt");
+        std::fs::write(&path, b"aaaabbbb").unwrap();
+
+        let sender_data = b"bbbbccaaaa";
+        let algorithm = StrongHashAlgorithm::default();
+        let mut instructions = instructions_from_matches(&[(4, 0), (0, 6)], sender_data, 4);
+        let last = instructions.split_off(2);
+
+        let mut patch = Patch::new(&path, algorithm).unwrap();
+        patch.apply(&instructions, b"aaaabbbb").unwrap();
+        // Batches must pick up where the previous one ended.
+        assert!(matches!(
+            patch.apply(&instructions, b"aaaabbbb"),
+            Err(SyncrError::InstructionOutOfOrder { offset: 0, expected: 6 })
+        ));
+        patch.apply(&last, b"aaaabbbb").unwrap();
+        assert_eq!(patch.len(), sender_data.len());
+        patch.finish(algorithm.digest(sender_data)).unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), sender_data);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 1);
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    proptest! {
+        #[test]
+        fn instructions_rebuild_the_sender_file(
+            receiver_data in prop::collection::vec(0u8..=3, 0..=200),
+            sender_data in prop::collection::vec(0u8..=3, 0..=200),
+            block_size in 1usize..=8,
+        ) {
+            let matches = naive_matches(&receiver_data, &sender_data, block_size);
+            let instructions = instructions_from_matches(&matches, &sender_data, block_size);
+            prop_assert_eq!(reconstruct(&instructions, &receiver_data), sender_data);
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! The contents of the files being synced.
+//!
+//! With the `mmap` feature, files are memory-mapped instead of read into
+//! memory up front, so the checksums and the matcher can start on a large file
+//! right away, and only the pages they touch are ever loaded.
+//!
+//! A mapped file must not be truncated by anyone else while it's in use.
+//! `syncrd` never modifies a file in place (see [`crate::delta::patch_file`]),
+//! so this only matters for other programs writing to the same files.
+
+use std::ops::Deref;
+use std::path::Path;
+
+
+/// The contents of a file, either mapped or read into memory.
+#[derive(Debug, Default)]
+pub struct FileData {
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
+    data: Vec<u8>,
+}
+
+impl FileData {
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        #[cfg(feature = "mmap")]
+        {
+            let file = std::fs::File::open(path)?;
+            // Mapping an empty file fails on some platforms.
+            if file.metadata()?.len() == 0 {
+                return Ok(Self::default());
+            }
+            // Safety: see the module documentation.
+            let map = unsafe { memmap2::Mmap::map(&file)? };
+            Ok(Self { map: Some(map), data: vec![] })
+        }
+        #[cfg(not(feature = "mmap"))]
+        Ok(Self { data: std::fs::read(path)? })
+    }
+}
+
+impl From<Vec<u8>> for FileData {
+    fn from(data: Vec<u8>) -> Self {
+        Self {
+            #[cfg(feature = "mmap")]
+            map: None,
+            data,
+        }
+    }
+}
+
+impl Deref for FileData {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        #[cfg(feature = "mmap")]
+        if let Some(map) = &self.map {
+            return map;
+        }
+        &self.data
+    }
+}
+
+impl AsRef<[u8]> for FileData {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_read_whole() {
+        let path = std::env::temp_dir().join(format!("syncr-file-data-test-{}", std::process::id()));
+        std::fs::write(&path, b"some contents").unwrap();
+        assert_eq!(&*FileData::open(&path).unwrap(), b"some contents");
+
+        std::fs::write(&path, b"").unwrap();
+        assert!(FileData::open(&path).unwrap().is_empty());
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(FileData::open(&path).is_err());
+    }
+}
@@ -0,0 +1,301 @@
+//! The opening handshake of a session.
+//!
+//! As soon as a connection is established, both peers send a [`Hello`]
+//! describing what they support. Each side then computes the same
+//! [`Agreement`] from the pair of `Hello`s, so both know which strong hash
+//! and optional features will be used for the session, or fail with a clear
+//! error if they have nothing in common.
+//!
+//! The sender may also ask for a block size, modulus, rolling hash and strong
+//! checksum length, and restrict the strong hashes it offers to the one it
+//! wants. Whatever it leaves open is picked by the receiver once it knows
+//! which file (and module) the session is about, and sent along with the block
+//! signatures.
+
+use serde::{Serialize, Deserialize};
+
+use crate::{rolling_hash::RollingHashAlgorithm, strong_checksum::{StrongHash, StrongHashAlgorithm}, ChecksumConfig, SyncrError};
+
+/// The version of the protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// The receiver may ask for the whole file if the reconstructed file
+/// doesn't match the sender's digest.
+pub const WHOLE_FILE_FALLBACK: &str = "whole-file-fallback";
+
+/// The receiver requires the sender to authenticate (see `syncr::auth`)
+/// before naming a file. Receivers only advertise it when they require it.
+pub const AUTHENTICATION: &str = "authentication";
+
+/// The optional features supported by this build.
+pub const FEATURES: &[&str] = &[WHOLE_FILE_FALLBACK, AUTHENTICATION];
+
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Hello {
+    pub version: u32,
+    /// The supported strong hashes, in order of preference.
+    pub strong_hashes: Vec<String>,
+    /// The block size the sender wants to use, if any.
+    pub block_size: Option<usize>,
+    /// The modulus the sender wants to use, if any.
+    pub modulus: Option<u32>,
+    pub features: Vec<String>,
+    /// The length of the strong checksums the sender wants to use, if any.
+    #[serde(default)]
+    pub strong_length: Option<usize>,
+    /// The rolling hash the sender wants to use, if any.
+    #[serde(default)]
+    pub rolling_hash: Option<RollingHashAlgorithm>,
+}
+
+impl Hello {
+    /// Describe everything this build supports, asking for the given block
+    /// size and modulus (if any).
+    pub fn new(block_size: Option<usize>, modulus: Option<u32>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            strong_hashes: StrongHashAlgorithm::ALL.iter().map(|hash| hash.name().to_string()).collect(),
+            block_size,
+            modulus,
+            features: FEATURES.iter().map(|feature| feature.to_string()).collect(),
+            strong_length: None,
+            rolling_hash: None,
+        }
+    }
+}
+
+
+/// The common set of parameters both peers agreed on for the session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Agreement {
+    /// The strong hashes both peers support, in the sender's order of
+    /// preference.
+    pub strong_hashes: Vec<StrongHashAlgorithm>,
+    /// The block size the sender asked for, if any.
+    pub block_size: Option<usize>,
+    /// The modulus the sender asked for, if any.
+    pub modulus: Option<u32>,
+    /// The strong checksum length the sender asked for, if any.
+    pub strong_length: Option<usize>,
+    /// The rolling hash the sender asked for, if any.
+    pub rolling_hash: Option<RollingHashAlgorithm>,
+    pub features: Vec<String>,
+}
+
+impl Agreement {
+    pub fn supports(&self, feature: &str) -> bool {
+        self.features.iter().any(|f| f == feature)
+    }
+
+    /// The checksum configuration the receiver should use: whatever the
+    /// sender asked for, and `default` for the rest. If both peers don't
+    /// support the default strong hash, the sender's favourite is used.
+    pub fn config(&self, default: &ChecksumConfig) -> ChecksumConfig {
+        let strong_hash = match self.strong_hashes.contains(&default.strong_hash) {
+            true => default.strong_hash,
+            false => self.strong_hashes.first().copied().unwrap_or(default.strong_hash),
+        };
+        ChecksumConfig {
+            block_size: self.block_size.unwrap_or(default.block_size),
+            modulus: self.modulus.unwrap_or(default.modulus),
+            rolling_hash: self.rolling_hash.unwrap_or(default.rolling_hash),
+            strong_hash,
+            strong_length: self.strong_length.or(default.strong_length),
+        }
+    }
+
+    /// Check the checksum configuration picked by the receiver.
+    pub fn check(&self, config: &ChecksumConfig) -> crate::Result<()> {
+        config.validate()?;
+        if self.config(config) != *config {
+            // The receiver ignored what the sender asked for.
+            return Err(SyncrError::InvalidChecksumConfig {
+                block_size: config.block_size,
+                modulus: config.modulus,
+                rolling_hash: config.rolling_hash,
+                strong_hash: config.strong_hash,
+                strong_length: config.strong_length,
+            });
+        }
+        Ok(())
+    }
+}
+
+
+/// Agree on one common set of parameters given both peers' `Hello`s.
+///
+/// Both peers call this with the same arguments (regardless of which side
+/// they're on), so they always come to the same conclusion.
+pub fn negotiate(client: &Hello, server: &Hello) -> crate::Result<Agreement> {
+    if client.version != server.version {
+        return Err(SyncrError::IncompatibleProtocolVersion {
+            client: client.version,
+            server: server.version,
+        });
+    }
+
+    let strong_hashes: Vec<_> = client
+        .strong_hashes
+        .iter()
+        .filter(|hash| server.strong_hashes.contains(hash))
+        .filter_map(|hash| StrongHashAlgorithm::from_name(hash))
+        .collect();
+    if strong_hashes.is_empty() {
+        return Err(SyncrError::NoCommonStrongHash {
+            client: client.strong_hashes.join(", "),
+            server: server.strong_hashes.join(", "),
+        });
+    }
+
+    let agreement = Agreement {
+        strong_hashes,
+        block_size: client.block_size,
+        modulus: client.modulus,
+        strong_length: client.strong_length,
+        rolling_hash: client.rolling_hash,
+        features: client
+            .features
+            .iter()
+            .filter(|feature| server.features.contains(feature))
+            .cloned()
+            .collect(),
+    };
+
+    // Fail early if the sender asked for something no receiver could use.
+    agreement.check(&agreement.config(&ChecksumConfig::default()))?;
+    Ok(agreement)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_with_the_same_build_agree() {
+        let client = Hello::new(Some(512), None);
+        let server = Hello::new(None, None);
+        let agreement = negotiate(&client, &server).unwrap();
+
+        assert_eq!(agreement.strong_hashes, StrongHashAlgorithm::ALL);
+        assert!(agreement.supports(WHOLE_FILE_FALLBACK));
+
+        let config = agreement.config(&ChecksumConfig { block_size: 2048, modulus: 1 << 15, ..Default::default() });
+        assert_eq!(config, ChecksumConfig { block_size: 512, modulus: 1 << 15, ..Default::default() });
+        assert!(agreement.check(&config).is_ok());
+    }
+
+    #[test]
+    fn the_receiver_may_not_override_the_sender() {
+        let agreement = negotiate(&Hello::new(Some(512), None), &Hello::new(None, None)).unwrap();
+
+        assert!(agreement.check(&ChecksumConfig { block_size: 512, modulus: 1 << 10, ..Default::default() }).is_ok());
+        assert!(agreement.check(&ChecksumConfig { block_size: 1024, modulus: 1 << 10, ..Default::default() }).is_err());
+        assert!(agreement.check(&ChecksumConfig { block_size: 512, modulus: 0, ..Default::default() }).is_err());
+        assert!(agreement.check(&ChecksumConfig { block_size: 512, strong_length: Some(17), ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn the_sender_may_pick_the_strong_hash() {
+        let wanted = *StrongHashAlgorithm::ALL.last().unwrap();
+        let mut client = Hello::new(None, None);
+        client.strong_hashes = vec![wanted.name().to_string()];
+        client.strong_length = Some(8);
+        let agreement = negotiate(&client, &Hello::new(None, None)).unwrap();
+
+        let config = agreement.config(&ChecksumConfig::default());
+        assert_eq!((config.strong_hash, config.strong_length), (wanted, Some(8)));
+        assert!(agreement.check(&config).is_ok());
+        if wanted != StrongHashAlgorithm::default() {
+            assert!(agreement.check(&ChecksumConfig { strong_length: Some(8), ..Default::default() }).is_err());
+        }
+    }
+
+    #[test]
+    fn the_sender_may_pick_the_rolling_hash() {
+        let default = ChecksumConfig { rolling_hash: RollingHashAlgorithm::Adler32, ..Default::default() };
+        let agreement = negotiate(&Hello::new(None, None), &Hello::new(None, None)).unwrap();
+        assert_eq!(agreement.config(&default).rolling_hash, RollingHashAlgorithm::Adler32);
+
+        let mut client = Hello::new(None, None);
+        client.rolling_hash = Some(RollingHashAlgorithm::Buzhash);
+        let agreement = negotiate(&client, &Hello::new(None, None)).unwrap();
+        let config = agreement.config(&default);
+        assert_eq!(config.rolling_hash, RollingHashAlgorithm::Buzhash);
+        assert!(agreement.check(&config).is_ok());
+        assert!(agreement.check(&default).is_err());
+    }
+
+    #[test]
+    fn the_receiver_derives_the_strong_length_unless_asked() {
+        let agreement = negotiate(&Hello::new(None, None), &Hello::new(None, None)).unwrap();
+        let config = agreement.config(&ChecksumConfig::default()).for_file_size(1 << 20);
+        assert_eq!(config.strong_length, Some(3));
+        assert!(agreement.check(&config).is_ok());
+
+        let mut client = Hello::new(None, None);
+        client.strong_length = Some(8);
+        let agreement = negotiate(&client, &Hello::new(None, None)).unwrap();
+        let config = agreement.config(&ChecksumConfig::default()).for_file_size(1 << 20);
+        assert_eq!(config.strong_length, Some(8));
+        assert!(agreement.check(&config.for_file_size(0)).is_ok());
+    }
+
+    #[test]
+    fn features_are_intersected() {
+        let client = Hello::new(None, None);
+        let mut server = Hello::new(None, None);
+        server.features = vec!["something-else".to_string()];
+
+        let agreement = negotiate(&client, &server).unwrap();
+        assert!(agreement.features.is_empty());
+        assert!(!agreement.supports(WHOLE_FILE_FALLBACK));
+    }
+
+    #[test]
+    fn mismatched_versions_fail() {
+        let client = Hello::new(None, None);
+        let mut server = Hello::new(None, None);
+        server.version += 1;
+
+        assert!(matches!(negotiate(&client, &server), Err(SyncrError::IncompatibleProtocolVersion { .. })));
+    }
+
+    #[test]
+    fn older_peers_are_rejected() {
+        // Version 2 peers don't know about the rolling hash in the `Hello`,
+        // and version 3 peers send all the instructions in a single message.
+        for version in [2, 3] {
+            let client = Hello::new(None, None);
+            let mut server = Hello::new(None, None);
+            server.version = version;
+
+            assert!(matches!(
+                negotiate(&client, &server),
+                Err(SyncrError::IncompatibleProtocolVersion { client: PROTOCOL_VERSION, server }) if server == version
+            ));
+            assert!(matches!(
+                negotiate(&server, &client),
+                Err(SyncrError::IncompatibleProtocolVersion { client, server: PROTOCOL_VERSION }) if client == version
+            ));
+        }
+    }
+
+    #[test]
+    fn no_common_strong_hash_fails() {
+        let client = Hello::new(None, None);
+        let mut server = Hello::new(None, None);
+        server.strong_hashes = vec!["unknown".to_string()];
+
+        assert!(matches!(negotiate(&client, &server), Err(SyncrError::NoCommonStrongHash { .. })));
+    }
+
+    #[test]
+    fn invalid_checksum_config_fails() {
+        let client = Hello::new(Some(0), None);
+        let server = Hello::new(None, None);
+
+        assert!(matches!(negotiate(&client, &server), Err(SyncrError::InvalidChecksumConfig { block_size: 0, .. })));
+    }
+}
@@ -1,19 +1,74 @@
-use itertools::Itertools;
-use network::Message;
-use strong_checksum::StrongCheckSum;
+use network::{ErrorCode, Message};
+use rolling_hash::RollingHashAlgorithm;
+use strong_checksum::{StrongCheckSum, StrongHashAlgorithm};
 use weak_checksum::WeakCheckSum;
 
 pub mod weak_checksum;
+pub mod rolling_hash;
 pub mod multisearch;
 pub mod strong_checksum;
 pub mod network;
+pub mod delta;
+pub mod file_data;
+pub mod handshake;
+pub mod session;
+pub mod auth;
+pub mod sandbox;
+pub mod config;
+pub mod locks;
+pub mod streaming;
+#[cfg(unix)]
+pub mod systemd;
+#[cfg(feature = "tls")]
+pub mod tls;
+use serde::{Serialize, Deserialize};
 use thiserror::Error;
 
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ChecksumConfig {
     pub block_size: usize,
+    /// The modulus of the `rsync` rolling hash (the others ignore it).
     pub modulus: u32,
+    pub rolling_hash: RollingHashAlgorithm,
+    pub strong_hash: StrongHashAlgorithm,
+    /// The length of each block's strong checksum, in bytes, or `None` to
+    /// derive it from the size of the file (see
+    /// [`ChecksumConfig::for_file_size`]).
+    pub strong_length: Option<usize>,
+}
+
+impl ChecksumConfig {
+    pub fn validate(&self) -> Result<()> {
+        // The weak checksum packs `a` and `b` into 16 bits each.
+        if self.block_size == 0
+            || self.modulus == 0
+            || self.modulus > 1 << 16
+            || self.strong_length.is_some_and(|length| !(1..=strong_checksum::MAX_LENGTH).contains(&length))
+        {
+            return Err(SyncrError::InvalidChecksumConfig {
+                block_size: self.block_size,
+                modulus: self.modulus,
+                rolling_hash: self.rolling_hash,
+                strong_hash: self.strong_hash,
+                strong_length: self.strong_length,
+            });
+        }
+        Ok(())
+    }
+
+    /// The configuration to use for a file of `file_size` bytes, with the
+    /// strong checksum length derived from it unless one was given.
+    pub fn for_file_size(self, file_size: usize) -> Self {
+        let weak_bits = WeakCheckSum::with_config(&self).bits();
+        Self {
+            strong_length: Some(self.strong_length.unwrap_or_else(|| {
+                strong_checksum::derived_length(file_size, self.block_size, weak_bits)
+            })),
+            ..self
+        }
+    }
 }
 
 impl Default for ChecksumConfig {
@@ -21,6 +76,9 @@ impl Default for ChecksumConfig {
         Self {
             block_size: 1000,
             modulus: 1 << 16,
+            rolling_hash: RollingHashAlgorithm::default(),
+            strong_hash: StrongHashAlgorithm::default(),
+            strong_length: None,
         }
     }
 }
@@ -72,6 +130,10 @@ impl Checksums for CheckSum {
         let strong_iter = self.strong.checksums_non_overlapping(data);
         Box::new(weak_iter.zip(strong_iter))
     }
+
+    fn block_size(&self) -> usize {
+        self.strong.block_size
+    }
 }
 
 
@@ -81,7 +143,11 @@ pub trait Checksums {
     /// Returns a rolling iterator over the checksums of the data.
     fn checksums<'buf>(&self, data: &'buf [u8]) -> Box<dyn Iterator<Item=Self::Output> + 'buf>;
     /// Returns a non-overlapping iterator over the checksums of the data.
+    ///
+    /// The last block may be shorter than the others.
     fn checksums_non_overlapping<'buf>(&self, data: &'buf [u8]) -> Box<dyn Iterator<Item=Self::Output> + 'buf>;
+    /// The size of the blocks the checksums are computed over.
+    fn block_size(&self) -> usize;
 }
 
 
@@ -102,8 +168,190 @@ pub enum SyncrError {
     SerializationError(#[from] rmp_serde::encode::Error),
     #[error("Deserialization Error: {0}")]
     DeserializationError(#[from] rmp_serde::decode::Error),
+    #[cfg(feature = "tls")]
+    #[error("TLS error: {0}")]
+    Tls(#[from] tokio_rustls::rustls::Error),
     #[error("Connection reset by peer.")]
     ConnectionResetByPeer,
+    #[error("Cannot replicate {length} bytes at offset {from_offset} from a file of {available} bytes.")]
+    ReplicateOutOfBounds {
+        from_offset: usize,
+        length: usize,
+        available: usize,
+    },
+    #[error("Reconstructed file digest {actual:032x} does not match the sender's digest {expected:032x}.")]
+    FileDigestMismatch {
+        expected: u128,
+        actual: u128,
+    },
+    #[error("Frame exceeds the maximum frame size of {0} bytes.")]
+    FrameTooLarge(usize),
+    #[error("{kind} message has {count} entries, more than the maximum of {max}.")]
+    TooManyEntries {
+        kind: &'static str,
+        count: usize,
+        max: usize,
+    },
+    #[error("Session exceeds the maximum of {0} literal bytes.")]
+    TooManyLiteralBytes(usize),
+    #[error("Incompatible protocol versions: client speaks version {client}, server speaks version {server}.")]
+    IncompatibleProtocolVersion {
+        client: u32,
+        server: u32,
+    },
+    #[error("No common strong hash: client supports [{client}], server supports [{server}].")]
+    NoCommonStrongHash {
+        client: String,
+        server: String,
+    },
+    #[error(
+        "Invalid checksum configuration: block size {block_size}, modulus {modulus}, {rolling_hash} and {strong_hash} checksums{}.",
+        .strong_length.map(|length| format!(" of {} bytes", length)).unwrap_or_default()
+    )]
+    InvalidChecksumConfig {
+        block_size: usize,
+        modulus: u32,
+        rolling_hash: RollingHashAlgorithm,
+        strong_hash: StrongHashAlgorithm,
+        strong_length: Option<usize>,
+    },
+    #[error("Block signatures carry {bytes} bytes of strong checksums for {count} blocks of {strong_length} bytes each.")]
+    MalformedSignatures {
+        count: usize,
+        strong_length: usize,
+        bytes: usize,
+    },
+    #[error("Peer sent an unexpected {kind} message while we were in the {state} state.")]
+    UnexpectedMessage {
+        kind: &'static str,
+        state: &'static str,
+    },
+    #[error("Connection closed while we were in the {0} state.")]
+    UnexpectedEndOfSession(&'static str),
+    #[error("Instruction at offset {offset} does not follow the previous one, which ended at offset {expected}.")]
+    InstructionOutOfOrder {
+        offset: usize,
+        expected: usize,
+    },
+    #[error("NewData instruction at offset {offset} declares {length} bytes but carries {actual}.")]
+    NewDataLengthMismatch {
+        offset: usize,
+        length: usize,
+        actual: usize,
+    },
+    #[error("Reconstructed file would exceed the maximum file size of {0} bytes.")]
+    FileTooLarge(usize),
+    #[error("Authentication failed for user {0:?}.")]
+    AuthenticationFailed(String),
+    #[error("The receiver requires authentication, but no credentials were given.")]
+    AuthenticationRequired,
+    #[error("Invalid secrets file {path}: {reason}")]
+    InvalidSecretsFile {
+        path: String,
+        reason: String,
+    },
+    #[error("Path {0:?} is outside of the served directory.")]
+    PathOutsideRoot(String),
+    #[error("Invalid configuration {path}: {reason}")]
+    InvalidConfig {
+        path: String,
+        reason: String,
+    },
+    #[error("Unknown module {0:?}.")]
+    UnknownModule(String),
+    #[error("Access to module {module:?} denied: {reason}.")]
+    AccessDenied {
+        module: String,
+        reason: &'static str,
+    },
+    #[error("Too many connections to module {0:?}.")]
+    TooManyConnections(String),
+    #[error("The server is already serving its maximum of {0} clients.")]
+    ServerBusy(usize),
+    #[error("The server is shutting down.")]
+    ShuttingDown,
+    #[error("Gave up after waiting {0:?} for the peer.")]
+    TimedOut(std::time::Duration),
+    #[error("Remote shell {command:?} {status}.")]
+    RemoteShellFailed {
+        command: String,
+        status: std::process::ExitStatus,
+    },
+    #[error("Peer reported an error ({code:?}): {message}")]
+    Remote {
+        code: ErrorCode,
+        message: String,
+    },
     #[error("SendError: {0}")]
     SyncMpScError(#[from] tokio::sync::mpsc::error::SendError<Message>),
+}
+
+impl SyncrError {
+    /// Whether the error was caused by a peer sending us something it
+    /// shouldn't have.
+    pub fn is_protocol_violation(&self) -> bool {
+        matches!(
+            self,
+            SyncrError::DeserializationError(_)
+            | SyncrError::ReplicateOutOfBounds { .. }
+            | SyncrError::FrameTooLarge(_)
+            | SyncrError::TooManyEntries { .. }
+            | SyncrError::TooManyLiteralBytes(_)
+            | SyncrError::MalformedSignatures { .. }
+            | SyncrError::UnexpectedMessage { .. }
+            | SyncrError::InstructionOutOfOrder { .. }
+            | SyncrError::NewDataLengthMismatch { .. }
+            | SyncrError::FileTooLarge(_)
+        )
+    }
+
+    /// The code describing this error to the peer.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            SyncrError::Remote { code, .. } => *code,
+            SyncrError::IncompatibleProtocolVersion { .. }
+            | SyncrError::NoCommonStrongHash { .. }
+            | SyncrError::InvalidChecksumConfig { .. } => ErrorCode::Incompatible,
+            SyncrError::FrameTooLarge(_)
+            | SyncrError::TooManyEntries { .. }
+            | SyncrError::TooManyLiteralBytes(_)
+            | SyncrError::FileTooLarge(_) => ErrorCode::LimitExceeded,
+            SyncrError::FileDigestMismatch { .. } => ErrorCode::VerificationFailed,
+            SyncrError::AuthenticationFailed(_)
+            | SyncrError::AuthenticationRequired => ErrorCode::AuthenticationFailed,
+            SyncrError::IoError(e) if e.kind() == std::io::ErrorKind::NotFound => ErrorCode::NotFound,
+            SyncrError::IoError(e) if e.kind() == std::io::ErrorKind::PermissionDenied => ErrorCode::PermissionDenied,
+            SyncrError::PathOutsideRoot(_)
+            | SyncrError::AccessDenied { .. } => ErrorCode::PermissionDenied,
+            SyncrError::UnknownModule(_) => ErrorCode::NotFound,
+            SyncrError::TooManyConnections(_)
+            | SyncrError::ServerBusy(_)
+            | SyncrError::TimedOut(_) => ErrorCode::LimitExceeded,
+            SyncrError::ShuttingDown => ErrorCode::Unavailable,
+            SyncrError::IoError(_)
+            | SyncrError::RemoteShellFailed { .. } => ErrorCode::Io,
+            e if e.is_protocol_violation() => ErrorCode::ProtocolViolation,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The process exit code to use when giving up because of this error.
+    pub fn exit_code(&self) -> u8 {
+        self.code().exit_code()
+    }
+
+    /// The `Message::Error` to send to the peer before closing the
+    /// connection because of this error, if any.
+    pub fn to_message(&self) -> Option<Message> {
+        match self {
+            // The peer is either gone, or already knows what went wrong.
+            SyncrError::ConnectionResetByPeer
+            | SyncrError::Remote { .. }
+            | SyncrError::SyncMpScError(_) => None,
+            _ => Some(Message::Error {
+                code: self.code(),
+                message: self.to_string(),
+            }),
+        }
+    }
 }
\ No newline at end of file
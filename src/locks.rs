@@ -0,0 +1,97 @@
+//! Locks on the files being updated by the daemon, so that concurrent
+//! sessions never rebuild the same file at the same time.
+//!
+//! A session locks its file as soon as it knows which one it is, before
+//! computing any block signatures, and keeps it locked until it's over. Any
+//! other session for the same file waits, and then works from the updated
+//! file.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OwnedMutexGuard;
+
+
+#[derive(Debug, Default)]
+pub struct FileLocks {
+    locks: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl FileLocks {
+    /// Wait until no other session holds the lock on `path`, and take it.
+    pub async fn lock(self: &Arc<Self>, path: &Path) -> FileLock {
+        let mutex = self
+            .locks
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default()
+            .clone();
+
+        FileLock {
+            locks: self.clone(),
+            path: path.to_path_buf(),
+            guard: Some(mutex.lock_owned().await),
+        }
+    }
+
+    /// The number of files currently locked or waited on.
+    pub fn len(&self) -> usize {
+        self.locks.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+
+/// The lock on a single file, released on drop.
+#[derive(Debug)]
+pub struct FileLock {
+    locks: Arc<FileLocks>,
+    path: PathBuf,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        let mut locks = self.locks.locks.lock().unwrap();
+        self.guard.take();
+        // Forget about the file unless someone else is waiting for it.
+        if locks.get(&self.path).is_some_and(|mutex| Arc::strong_count(mutex) == 1) {
+            locks.remove(&self.path);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn sessions_for_the_same_file_take_turns() {
+        let locks = Arc::new(FileLocks::default());
+        let first = locks.lock(Path::new("/a")).await;
+
+        let waiting = tokio::spawn({
+            let locks = locks.clone();
+            async move { locks.lock(Path::new("/a")).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        // Other files aren't affected.
+        let other = locks.lock(Path::new("/b")).await;
+        drop(other);
+
+        drop(first);
+        let second = waiting.await.unwrap();
+        assert_eq!(locks.len(), 1);
+        drop(second);
+        assert!(locks.is_empty());
+    }
+}
@@ -1,14 +1,24 @@
-use std::path::{PathBuf, Path};
+use std::ops::ControlFlow;
+use std::process::ExitCode;
 use std::sync::{Mutex, Arc};
-use std::time::Duration;
 
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::process::Command;
 use syncr::network::*;
-use tracing::{info, debug};
-use tracing_subscriber;
+use tracing::{debug, info};
 use syncr::{
-    Checksums,
-    CheckSum
+    auth::Credentials,
+    delta,
+    file_data::FileData,
+    handshake::{self, Agreement, Hello},
+    session::SenderState,
+    multisearch::Matcher,
+    rolling_hash::RollingHashAlgorithm,
+    strong_checksum::{StrongHash, StrongHashAlgorithm},
+    CheckSum,
+    ChecksumConfig,
+    SyncrError,
 };
 use clap::Parser;
 
@@ -24,8 +34,69 @@ pub struct Cli {
     pub file: String,
     #[clap(short, long, default_value = "test-remote.txt")]
     pub remote_file: String,
+    #[clap(long, help = "The host syncrd is listening on.", default_value = "localhost", conflicts_with = "rsh")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub host: String,
     #[clap(short, long, default_value_t = 8000)]
     pub port: u16,
+    #[clap(
+        short,
+        long,
+        help = "The size of each chunk to calculate a running checksum for. Picked by the receiver if not given."
+    )]
+    pub block_size: Option<usize>,
+    #[clap(short, long, help = "The modulus to use for the checksum. Picked by the receiver if not given.")]
+    pub modulus: Option<u32>,
+    #[clap(long, value_enum, help = "The strong hash to use. Picked by the receiver among those both sides support if not given.")]
+    pub strong_hash: Option<StrongHashAlgorithm>,
+    #[clap(long, help = "The length of the strong checksum of each block, in bytes (at most 16). Picked by the receiver (or derived from the size of its file) if not given.")]
+    pub strong_length: Option<usize>,
+    #[clap(long, value_enum, help = "The rolling hash to use for the weak checksums. Picked by the receiver if not given.")]
+    pub rolling_hash: Option<RollingHashAlgorithm>,
+    #[clap(
+        short = 'e',
+        long,
+        help = "Spawn this shell command (e.g. `ssh host syncrd --server`) and talk to it over its stdin/stdout instead of connecting over TCP."
+    )]
+    pub rsh: Option<String>,
+    #[clap(long, help = "The user to authenticate as, if the receiver requires it.", requires = "password_file")]
+    pub user: Option<String>,
+    #[clap(long, help = "Read the user's secret from the first line of this file.", requires = "user")]
+    pub password_file: Option<std::path::PathBuf>,
+    #[cfg(unix)]
+    #[clap(long, help = "Connect to syncrd over the Unix domain socket at this path instead of TCP.", conflicts_with = "rsh")]
+    pub socket: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "Connect over TLS, trusting the CAs in this PEM bundle.", conflicts_with = "rsh")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_ca: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "Present the certificate chain in this PEM file to the server.", requires_all = ["tls_ca", "tls_key"], conflicts_with = "rsh")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_cert: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "The private key of the client certificate, in PEM.", requires = "tls_cert", conflicts_with = "rsh")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_key: Option<std::path::PathBuf>,
+    #[cfg(feature = "tls")]
+    #[clap(long, help = "The name to check the server's certificate against. Defaults to the host.", requires = "tls_ca", conflicts_with = "rsh")]
+    #[cfg_attr(unix, clap(conflicts_with = "socket"))]
+    pub tls_server_name: Option<String>,
+}
+
+#[cfg(feature = "tls")]
+impl Cli {
+    pub fn tls_connector(&self) -> syncr::Result<Option<syncr::tls::TlsConnector>> {
+        let Some(ca) = &self.tls_ca else {
+            return Ok(None);
+        };
+        let identity = self.tls_cert.as_deref().zip(self.tls_key.as_deref());
+        Ok(Some(syncr::tls::connector(ca, identity)?))
+    }
+
+    pub fn tls_server_name(&self) -> &str {
+        self.tls_server_name.as_deref().unwrap_or(&self.host)
+    }
 }
 
 
@@ -94,11 +165,46 @@ pub struct Cli {
 
 #[derive(Debug)]
 pub struct ConnectionState {
+    /// The block size we ask the recipient for, if any.
+    pub block_size: Option<usize>,
+    /// The modulus we ask the recipient for, if any.
+    pub modulus: Option<u32>,
+    /// The strong hash we ask the recipient for, if any.
+    pub strong_hash: Option<StrongHashAlgorithm>,
+    /// The strong checksum length we ask the recipient for, if any.
+    pub strong_length: Option<usize>,
+    /// The rolling hash we ask the recipient for, if any.
+    pub rolling_hash: Option<RollingHashAlgorithm>,
     pub checksum: CheckSum,
-    pub weak_checksums: Vec<u32>,
     pub file_path: String,
     pub remote_file_path: String,
-    pub own_data: Vec<u8>,
+    pub own_data: FileData,
+    pub agreement: Option<Agreement>,
+    pub session: SenderState,
+    pub credentials: Option<Credentials>,
+}
+
+impl ConnectionState {
+    pub fn from_cli(cli: &Cli) -> syncr::Result<Self> {
+        let credentials = match (&cli.user, &cli.password_file) {
+            (Some(user), Some(path)) => Some(Credentials::from_password_file(user.clone(), path)?),
+            _ => None,
+        };
+        Ok(Self {
+            block_size: cli.block_size,
+            modulus: cli.modulus,
+            strong_hash: cli.strong_hash,
+            strong_length: cli.strong_length,
+            rolling_hash: cli.rolling_hash,
+            checksum: CheckSum::default(),
+            file_path: cli.file.clone(),
+            remote_file_path: cli.remote_file.clone(),
+            own_data: FileData::default(),
+            agreement: None,
+            session: SenderState::default(),
+            credentials,
+        })
+    }
 }
 
 
@@ -111,6 +217,57 @@ pub struct SingleConnection {
 
 impl SingleConnection {
 
+    pub fn own_hello(&self) -> Hello {
+        let state = self.state.lock().unwrap();
+        let mut hello = Hello::new(state.block_size, state.modulus);
+        if let Some(strong_hash) = state.strong_hash {
+            hello.strong_hashes = vec![strong_hash.name().to_string()];
+        }
+        hello.strong_length = state.strong_length;
+        hello.rolling_hash = state.rolling_hash;
+        hello
+    }
+
+    pub fn build_hello_msg(&self) -> Message {
+        Message::Hello(self.own_hello())
+    }
+
+    /// Agree on the parameters of the session with the recipient.
+    pub fn process_hello(&mut self, hello: Hello) -> syncr::Result<()> {
+        let agreement = handshake::negotiate(&self.own_hello(), &hello)?;
+        debug!("Agreed on {:?}", agreement);
+        self.state.lock().unwrap().agreement = Some(agreement);
+        Ok(())
+    }
+
+    /// Use the checksum configuration the recipient picked, as long as it's
+    /// the one we asked for.
+    pub fn set_checksum_config(&mut self, config: &ChecksumConfig) -> syncr::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        match &state.agreement {
+            Some(agreement) => agreement.check(config)?,
+            None => config.validate()?,
+        }
+        debug!("Using {:?}", config);
+        state.checksum = CheckSum::with_config(config);
+        Ok(())
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        let state = self.state.lock().unwrap();
+        state.agreement.as_ref().is_some_and(|agreement| agreement.supports(feature))
+    }
+
+    /// Prove to the recipient that we know our user's secret.
+    pub fn build_auth_response_msg(&self, nonce: &[u8]) -> syncr::Result<Message> {
+        let state = self.state.lock().unwrap();
+        let credentials = state.credentials.as_ref().ok_or(SyncrError::AuthenticationRequired)?;
+        Ok(Message::AuthResponse {
+            user: credentials.user.clone(),
+            mac: credentials.respond(nonce),
+        })
+    }
+
     pub fn build_filename_msg(&self) -> Message {
         let state = self.state.lock().unwrap();
         Message::FileName(state.remote_file_path.clone())
@@ -120,82 +277,217 @@ impl SingleConnection {
         Ok(())
     }
 
-    pub fn compute_our_checksums(&mut self) -> syncr::Result<()> {
+    pub fn read_own_data(&mut self) -> syncr::Result<()> {
         let mut state = self.state.lock().unwrap();
-        state.own_data = std::fs::read(&state.file_path)?;
-        state.weak_checksums = state.checksum.weak.checksums(&state.own_data).collect();
+        state.own_data = FileData::open(&state.file_path)?;
         Ok(())
     }
-    pub async fn send_weak_checksums_msg(&mut self) -> syncr::Result<()> {
-        self.compute_our_checksums()?;
+
+    /// Search our file for the recipient's blocks and return the
+    /// `(recipient_offset, our_offset)` pairs of the blocks we found.
+    pub fn find_matches(&self, signatures: &Signatures) -> syncr::Result<Vec<(usize, usize)>> {
         let state = self.state.lock().unwrap();
-        let msg = Message::WeakChecksums(state.weak_checksums.clone());
-        drop(state);
-        self.outbound_msg_tx.send(msg).await?;
-        Ok(())
+        let block_size = state.checksum.strong.block_size;
+        let signatures = signatures.unpack(state.checksum.strong.length)?;
+
+        let mut matcher = Matcher {
+            checksum: state.checksum,
+            ..Default::default()
+        };
+        matcher.compile_signatures(signatures);
+
+        Ok(
+            matcher
+            .find_blocks(&state.own_data)
+            .into_iter()
+            .map(|(block_index, offset)| (block_index * block_size, offset))
+            .collect()
+        )
     }
 
-    // TODO: Determine the block and in the order they should be recreated.
     pub fn given_indices_issue_list_of_instructions(&self, indices: &[(usize, usize)]) -> Vec<Instruction>{
         // The first index is the byte offset in the recipient that matches to the byte offset (the second index) in our file (the sender).
+        let state = self.state.lock().unwrap();
+        delta::instructions_from_matches(indices, &state.own_data, state.checksum.strong.block_size)
+    }
 
-        vec![]
+    /// The batches of instructions to rebuild our file, followed by its
+    /// digest.
+    pub fn build_instructions_msgs(&self, matches: &[(usize, usize)]) -> Vec<Message> {
+        let instructions = self.given_indices_issue_list_of_instructions(matches);
+        let state = self.state.lock().unwrap();
+        delta::batch_instructions(instructions)
+            .into_iter()
+            .map(|instructions| Message::Instructions { instructions })
+            .chain([Message::EndOfInstructions { digest: state.checksum.strong.digest(&state.own_data) }])
+            .collect()
     }
 
-    pub async fn run(mut self) -> syncr::Result<()> {
-        let _ = self.send_filename_msg().await;
-        tokio::time::sleep(Duration::from_micros(10)).await;
-        let _ = self.send_weak_checksums_msg().await;
+    pub async fn send_instructions_msgs(&mut self, matches: &[(usize, usize)]) -> syncr::Result<()> {
+        for msg in self.build_instructions_msgs(matches) {
+            self.outbound_msg_tx.send(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a single message from the recipient, returning
+    /// `ControlFlow::Break` once the session is over.
+    pub async fn process_message(&mut self, msg: Message) -> syncr::Result<ControlFlow<()>> {
+        self.state.lock().unwrap().session.receive(&msg)?;
+
+        match msg {
+            Message::Hello(hello) => {
+                self.process_hello(hello)?;
+                if self.supports(handshake::AUTHENTICATION) {
+                    // The file name has to wait until we're authenticated.
+                    self.state.lock().unwrap().session.expect_challenge();
+                } else {
+                    self.send_filename_msg().await?;
+                }
+            },
+            Message::AuthChallenge(nonce) => {
+                let msg = self.build_auth_response_msg(&nonce)?;
+                self.outbound_msg_tx.send(msg).await?;
+                self.send_filename_msg().await?;
+            },
+            Message::BlockSignatures { config, signatures } => {
+                self.set_checksum_config(&config)?;
+                let matches = self.find_matches(&signatures)?;
+                debug!("Recipient match info: {:?}", matches);
+                // Once we know the blocks that the recipient already
+                // has, we can determine the blocks that we need to send.
+
+                // We can then send the blocks in the order that they should be
+                // recreated by the recipient.
+
+                self.send_instructions_msgs(&matches).await?;
+            },
+            Message::WholeFileRequest => {
+                if !self.supports(handshake::WHOLE_FILE_FALLBACK) {
+                    return Err(SyncrError::UnexpectedMessage {
+                        kind: "WholeFileRequest",
+                        state: self.state.lock().unwrap().session.name(),
+                    });
+                }
+                // The recipient couldn't rebuild our file from the delta,
+                // so send it over in its entirety instead.
+                self.send_instructions_msgs(&[]).await?;
+            },
+            Message::Done => {
+                info!("{} is in sync.", self.state.lock().unwrap().remote_file_path);
+                return Ok(ControlFlow::Break(()));
+            },
+            Message::Error { code, message } => {
+                return Err(SyncrError::Remote { code, message });
+            },
+            // Anything else was already rejected by the session state.
+            _ => {}
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    /// Go through the whole session, from the handshake until the
+    /// recipient is in sync (or the connection is closed).
+    async fn process_session(&mut self) -> syncr::Result<()> {
+        self.read_own_data()?;
+        self.outbound_msg_tx.send(self.build_hello_msg()).await?;
 
         while let Some(msg) = self.inbound_msg_rx.recv().await {
-            match msg {
-                Message::StrongChecksumRequest(strong_checksum_indices) => {
-                    let state = self.state.lock().unwrap();
-                    let mut result = vec![];
-                    for idx in strong_checksum_indices {
-                        result.push((idx, state.checksum.strong.checksum_for_block(idx, &state.own_data)));
-                    }
-                    self.outbound_msg_tx.send(Message::StrongChecksums(result)).await?;
-                },
-                Message::Matches(matches) => {
-                    debug!("Recipient match info: {:?}", matches);
-                    // Once the recipient has sent us the blocks that it already
-                    // has, we can determine the blocks that we need to send.
-                    
-                    // We can then send the blocks in the order that they should be
-                    // recreated by the recipient.
-
-                    let instructions = self.given_indices_issue_list_of_instructions(&matches);
-                    self.outbound_msg_tx.send(Message::Instructions(instructions)).await?;
-                },
-                _ => {
+            if self.process_message(msg).await?.is_break() {
+                break;
+            }
+        }
+        self.state.lock().unwrap().session.closed()
+    }
 
-                }
+    /// The error the recipient hung up on us with, if any.
+    fn remote_error(&mut self) -> Option<SyncrError> {
+        while let Ok(msg) = self.inbound_msg_rx.try_recv() {
+            if let Message::Error { code, message } = msg {
+                return Some(SyncrError::Remote { code, message });
             }
         }
+        None
+    }
 
+    pub async fn run(mut self) -> syncr::Result<()> {
+        if let Err(error) = self.process_session().await {
+            // The recipient may have closed the connection (e.g. because it's
+            // busy) before we even got to say anything.
+            let error = match error {
+                SyncrError::SyncMpScError(_) => self.remote_error().unwrap_or(error),
+                error => error,
+            };
+            // Let the recipient know why we're hanging up on them.
+            if let Some(reply) = error.to_message() {
+                let _ = self.outbound_msg_tx.send(reply).await;
+            }
+            return Err(error);
+        }
         Ok(())
     }
 }
 
 
-#[tokio::main]
-pub async fn main() -> syncr::Result<()> {
-    tracing_subscriber::fmt::init();
-    let cli = Cli::parse();
-    let stream = TcpStream::connect(format!("0.0.0.0:{}", cli.port)).await?;
+pub async fn sync(cli: Cli) -> syncr::Result<()> {
+    // Fail early (e.g. on a bad password file) rather than once we've connected.
+    let state = ConnectionState::from_cli(&cli)?;
+
+    if let Some(command) = cli.rsh.clone() {
+        debug!("Spawning {:?}", command);
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&command)
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let read_half = child.stdout.take().expect("the child's stdout is piped");
+        let write_half = child.stdin.take().expect("the child's stdin is piped");
+        let result = sync_over(read_half, write_half, state).await;
+
+        // Our end of the pipes is closed by now, so the remote exits too. If
+        // it failed without telling us why (e.g. the command doesn't exist),
+        // its exit status says more than whatever the session failed with.
+        let status = child.wait().await?;
+        if !status.success() && !matches!(result, Err(SyncrError::Remote { .. })) {
+            return Err(SyncrError::RemoteShellFailed { command, status });
+        }
+        return result;
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = cli.socket.clone() {
+        let stream = tokio::net::UnixStream::connect(&path).await?;
+        let (read_half, write_half) = stream.into_split();
+        return sync_over(read_half, write_half, state).await;
+    }
+
+    let stream = TcpStream::connect((cli.host.as_str(), cli.port)).await?;
+
+    #[cfg(feature = "tls")]
+    if let Some(connector) = cli.tls_connector()? {
+        let stream = connector.connect(syncr::tls::server_name(cli.tls_server_name())?, stream).await?;
+        let (read_half, write_half) = tokio::io::split(stream);
+        return sync_over(read_half, write_half, state).await;
+    }
+
+    let (read_half, write_half) = stream.into_split();
+    sync_over(read_half, write_half, state).await
+}
+
 
+/// Go through a whole session with the recipient over any transport.
+pub async fn sync_over<R, W>(read_half: R, write_half: W, state: ConnectionState) -> syncr::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
     let (inbound_msg_tx, inbound_msg_rx) = tokio::sync::mpsc::channel(100);
     let (outbound_msg_tx, outbound_msg_rx) = tokio::sync::mpsc::channel(100);
 
-    let connection = Connection::new(stream, inbound_msg_tx);
-    let state: ConnectionState = ConnectionState { 
-        checksum: CheckSum::default(), 
-        weak_checksums: vec![],
-        file_path: cli.file,
-        remote_file_path: cli.remote_file,
-        own_data: vec![]
-    };
+    let connection = Connection::from_parts(read_half, write_half, inbound_msg_tx, Limits::default());
 
     let single_connection = SingleConnection {
         outbound_msg_tx,
@@ -203,7 +495,22 @@ pub async fn main() -> syncr::Result<()> {
         state: Arc::new(Mutex::new(state)),
     };
 
-    let _ = tokio::join!(connection.run(outbound_msg_rx), single_connection.run());
+    let (connection_result, session_result) = tokio::join!(connection.run(outbound_msg_rx), single_connection.run());
+    session_result?;
+    connection_result
+}
+
 
-    Ok(())
+#[tokio::main]
+pub async fn main() -> ExitCode {
+    tracing_subscriber::fmt::init();
+    let cli = Cli::parse();
+
+    match sync(cli).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
 }
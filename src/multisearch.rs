@@ -24,29 +24,55 @@ impl Matcher {
 
     pub fn compile(&mut self, data: &[u8]) {
         let checksums = self.checksum.checksums(data).collect::<Vec<_>>();
-        let mut hash_table = HashMap::new();
-
-        for (offset, &checksum) in checksums.iter().enumerate() {
-
-            let checksum_hash: u16 = weak_hash(checksum.0);
-
-            hash_table
-            .entry(checksum_hash)
-            .and_modify(|m: &mut HashMap<u32, Vec<usize>>| {
-                m
-                .entry(checksum.0)
-                .and_modify(|strong_hashes| {
-                    strong_hashes.push(offset);
-                })
-                .or_insert(vec![offset]);
-                
-            })
-            .or_insert_with(|| HashMap::from_iter([(checksum.0, vec![offset])]));
-        }
-        self.hash_table = hash_table;
+        self.hash_table = build_hash_table(checksums.iter().map(|&(weak, _)| weak));
         self.strong_hashes = checksums.iter().map(|&(_, strong)| strong).collect();
     }
 
+    /// Index the non-overlapping `(weak, strong)` block signatures of some
+    /// other file so that [`Matcher::find_blocks`] can search for them.
+    pub fn compile_signatures(&mut self, signatures: impl IntoIterator<Item=(u32, u128)>) {
+        let (weak_hashes, strong_hashes): (Vec<u32>, Vec<u128>) = signatures.into_iter().unzip();
+        self.hash_table = build_hash_table(weak_hashes);
+        self.strong_hashes = strong_hashes;
+    }
+
+    /// Roll over every byte offset of `data` looking for the blocks compiled
+    /// with [`Matcher::compile_signatures`].
+    ///
+    /// Returns `(block_index, byte_offset)` pairs, where `byte_offset` is the
+    /// offset in `data` at which the block with the given index was found.
+    /// Once a block is found, the search resumes right after it, so the
+    /// returned regions of `data` never overlap.
+    pub fn find_blocks(&self, data: &[u8]) -> Vec<(usize, usize)> {
+        let block_size = self.checksum.strong.block_size;
+        let mut matches = Vec::new();
+        let mut weak_checksums = self.checksum.weak.checksums(data).enumerate();
+
+        while let Some((byte_offset, weak)) = weak_checksums.next() {
+            let candidates = match self.candidates(weak) {
+                Some(candidates) => candidates,
+                None => continue,
+            };
+
+            // The weak checksum matches, so check the strong checksum.
+            let strong = self.checksum.strong.hash(&data[byte_offset..data.len().min(byte_offset + block_size)]);
+            if let Some(&block_index) = candidates.iter().find(|&&index| self.strong_hashes[index] == strong) {
+                matches.push((block_index, byte_offset));
+                // Skip over the rest of the block we just found.
+                if block_size > 1 {
+                    weak_checksums.nth(block_size - 2);
+                }
+            }
+        }
+        matches
+    }
+
+    /// The indices of the compiled checksums that have the given weak checksum.
+    fn candidates(&self, weak: u32) -> Option<&Vec<usize>> {
+        // First, check the 16-bit hash, then the 32-bit hash.
+        self.hash_table.get(&weak_hash(weak))?.get(&weak)
+    }
+
     pub fn find_matches(&self, hashes_by_block: impl IntoIterator<Item=(u32, u128)>) -> Vec<(usize, usize)> {
         let mut matches = Vec::new();
         
@@ -83,9 +109,33 @@ impl Matcher {
 }
 
 
+fn build_hash_table(weak_hashes: impl IntoIterator<Item=u32>) -> HashMap<u16, HashMap<u32, Vec<usize>>> {
+    let mut hash_table = HashMap::new();
+
+    for (offset, checksum) in weak_hashes.into_iter().enumerate() {
+
+        let checksum_hash: u16 = weak_hash(checksum);
+
+        hash_table
+        .entry(checksum_hash)
+        .and_modify(|m: &mut HashMap<u32, Vec<usize>>| {
+            m
+            .entry(checksum)
+            .and_modify(|strong_hashes| {
+                strong_hashes.push(offset);
+            })
+            .or_insert(vec![offset]);
+
+        })
+        .or_insert_with(|| HashMap::from_iter([(checksum, vec![offset])]));
+    }
+    hash_table
+}
+
+
 pub fn stuff() {
     let mut data = vec!["a"; 1_003].join("");
-    data.push_str("b");
+    data.push('b');
 
     let mut matcher = Matcher::new();
     matcher.compile(data.as_bytes());
@@ -113,4 +163,44 @@ mod tests {
     fn test_stuff() {
         stuff();
     }
+
+    #[test]
+    fn find_blocks_of_shifted_data() {
+        let config = crate::ChecksumConfig { block_size: 4, modulus: 1 << 16, ..Default::default() };
+        let mut matcher = Matcher {
+            checksum: CheckSum::with_config(&config),
+            ..Default::default()
+        };
+        let receiver_data = b"abcdefghijkl";
+        matcher.compile_signatures(matcher.checksum.checksums_non_overlapping(receiver_data));
+
+        let sender_data = b"xxefghabcdyijklz";
+        assert_eq!(matcher.find_blocks(sender_data), vec![(1, 2), (0, 6), (2, 11)]);
+    }
+
+    #[test]
+    fn find_blocks_does_not_overlap() {
+        let config = crate::ChecksumConfig { block_size: 4, modulus: 1 << 16, ..Default::default() };
+        let mut matcher = Matcher {
+            checksum: CheckSum::with_config(&config),
+            ..Default::default()
+        };
+        matcher.compile_signatures(matcher.checksum.checksums_non_overlapping(b"aaaa"));
+
+        assert_eq!(matcher.find_blocks(b"aaaaaaaaaa"), vec![(0, 0), (0, 4)]);
+    }
+
+    #[test]
+    fn find_blocks_with_every_rolling_hash() {
+        for &rolling_hash in crate::rolling_hash::RollingHashAlgorithm::ALL {
+            let config = crate::ChecksumConfig { block_size: 4, rolling_hash, ..Default::default() };
+            let mut matcher = Matcher {
+                checksum: CheckSum::with_config(&config),
+                ..Default::default()
+            };
+            matcher.compile_signatures(matcher.checksum.checksums_non_overlapping(b"abcdefghijkl"));
+
+            assert_eq!(matcher.find_blocks(b"xxefghabcdyijklz"), vec![(1, 2), (0, 6), (2, 11)], "{}", rolling_hash);
+        }
+    }
 }
\ No newline at end of file
@@ -1,44 +1,190 @@
-use tokio::{net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
-use tokio::io::{BufWriter, BufReader};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::net::{tcp::{OwnedReadHalf, OwnedWriteHalf}, TcpStream};
+use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite, LengthDelimitedCodec, LengthDelimitedCodecError};
+use futures::{SinkExt, StreamExt};
 use serde::{
+    de::{Error as _, SeqAccess, Visitor},
+    Deserializer,
     Serialize,
     Deserialize
 };
-use bytes::{BytesMut, Buf};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use bytes::{Bytes, BytesMut};
 use tracing::trace;
+use crate::{ChecksumConfig, SyncrError};
+use crate::handshake::Hello;
 
+
+/// Caps on what a peer may send us, so a misbehaving or malicious peer
+/// can't make us allocate unbounded memory.
+#[derive(Debug, Copy, Clone)]
+pub struct Limits {
+    /// The maximum size of a single incoming frame, in bytes.
+    ///
+    /// Instructions are streamed in batches that fit in [`MIN_FRAME_SIZE`], so
+    /// this doesn't cap the size of a file. Outgoing frames aren't capped:
+    /// it's up to the peer to decide what it accepts.
+    pub max_frame_size: usize,
+    /// The maximum number of entries in a `BlockSignatures` message.
+    pub max_signatures: usize,
+    /// The maximum number of entries in an `Instructions` message.
+    pub max_instructions: usize,
+    /// The maximum number of literal bytes sent over the whole session.
+    pub max_literal_bytes: usize,
+    /// The maximum size of a file rebuilt from a stream of `Instructions`.
+    pub max_file_size: usize,
+}
+
+/// The smallest frame size a receiver may accept, so that a batch of
+/// instructions always fits in a frame.
+///
+/// Literal bytes are encoded as a msgpack array, which takes up to two bytes
+/// per byte, on top of the other instructions of the batch.
+pub const MIN_FRAME_SIZE: usize = 4 * crate::delta::BATCH_LITERAL_BYTES;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 256 * 1024 * 1024,
+            max_signatures: 1 << 22,
+            max_instructions: 1 << 22,
+            max_literal_bytes: 1024 * 1024 * 1024,
+            max_file_size: 4 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl Limits {
+    /// Check that the limits leave room for the messages of a session.
+    pub fn validate(&self) -> crate::Result<()> {
+        if self.max_frame_size < MIN_FRAME_SIZE {
+            return Err(SyncrError::InvalidConfig {
+                path: "--max-frame-size".to_string(),
+                reason: format!("must be at least {} bytes", MIN_FRAME_SIZE),
+            });
+        }
+        Ok(())
+    }
+
+    /// Check an incoming message against the limits, given the number of
+    /// literal bytes received so far in the session and the size of the file
+    /// rebuilt so far (which are both updated).
+    pub fn check(&self, message: &Message, literal_bytes: &mut usize, file_size: &mut usize) -> crate::Result<()> {
+        let (count, max) = match message {
+            Message::BlockSignatures { signatures, .. } => (signatures.len(), self.max_signatures),
+            Message::Instructions { instructions, .. } => (instructions.len(), self.max_instructions),
+            // The next instructions (if any) rebuild the whole file from scratch.
+            Message::EndOfInstructions { .. } => {
+                *file_size = 0;
+                return Ok(());
+            },
+            _ => return Ok(()),
+        };
+        if count > max {
+            return Err(SyncrError::TooManyEntries { kind: message.kind(), count, max });
+        }
+
+        if let Message::Instructions { instructions } = message {
+            for instruction in instructions {
+                match instruction {
+                    Instruction::NewData { bytes, .. } => {
+                        *literal_bytes = literal_bytes.saturating_add(bytes.len());
+                        *file_size = file_size.saturating_add(bytes.len());
+                    },
+                    Instruction::Replicate { length, .. } => {
+                        *file_size = file_size.saturating_add(*length);
+                    }
+                }
+            }
+            if *literal_bytes > self.max_literal_bytes {
+                return Err(SyncrError::TooManyLiteralBytes(self.max_literal_bytes));
+            }
+            if *file_size > self.max_file_size {
+                return Err(SyncrError::FileTooLarge(self.max_file_size));
+            }
+        }
+        Ok(())
+    }
+}
+
+
+/// A connection to a peer over any transport that can be split into an
+/// `AsyncRead` and an `AsyncWrite` half (TCP by default).
 #[derive(Debug)]
-pub struct Connection {
-    // The `TcpStream`. It is decorated with a `BufWriter`, which provides write
-    // level buffering. The `BufWriter` implementation provided by Tokio is
-    // sufficient for our needs.
+pub struct Connection<R = OwnedReadHalf, W = OwnedWriteHalf> {
     pub inbound_message_tx: tokio::sync::mpsc::Sender<Message>,
-    pub read_half: BufReader<OwnedReadHalf>,
-    pub write_half: BufWriter<OwnedWriteHalf>,
-
-    // // The buffer for reading frames.
-    pub buffer: BytesMut,
+    // Both halves of the transport are decorated with a `MessageCodec`,
+    // which takes care of the framing and the buffering of messages.
+    pub reader: FramedRead<R, MessageCodec>,
+    pub writer: FramedWrite<W, MessageCodec>,
+    pub limits: Limits,
+    // The number of literal bytes received so far.
+    pub literal_bytes: usize,
+    // The size of the file rebuilt from the instructions received so far.
+    pub file_size: usize,
 }
 
 impl Connection {
     pub fn new(
         stream: TcpStream,
         inbound_message_tx: tokio::sync::mpsc::Sender<Message>,
+    ) -> Self {
+        Self::with_limits(stream, inbound_message_tx, Limits::default())
+    }
+
+    pub fn with_limits(
+        stream: TcpStream,
+        inbound_message_tx: tokio::sync::mpsc::Sender<Message>,
+        limits: Limits,
     ) -> Self {
         let (read_half, write_half) = stream.into_split();
+        Self::from_parts(read_half, write_half, inbound_message_tx, limits)
+    }
+}
+
+impl<S> Connection<ReadHalf<S>, WriteHalf<S>>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    /// Speak the protocol over a single bidirectional stream, such as a Unix
+    /// socket, a TLS stream or a `tokio::io::duplex` pipe.
+    pub fn from_stream(
+        stream: S,
+        inbound_message_tx: tokio::sync::mpsc::Sender<Message>,
+        limits: Limits,
+    ) -> Self {
+        let (read_half, write_half) = tokio::io::split(stream);
+        Self::from_parts(read_half, write_half, inbound_message_tx, limits)
+    }
+}
+
+impl<R, W> Connection<R, W>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    /// Speak the protocol over a separate reader and writer, such as the
+    /// stdout and stdin of a child process.
+    pub fn from_parts(
+        read_half: R,
+        write_half: W,
+        inbound_message_tx: tokio::sync::mpsc::Sender<Message>,
+        limits: Limits,
+    ) -> Self {
         Self {
             inbound_message_tx,
-            read_half: BufReader::new(read_half),
-            write_half: BufWriter::new(write_half),
-            buffer: BytesMut::with_capacity(4 * 1024),
+            reader: FramedRead::new(read_half, MessageCodec::with_limits(limits)),
+            writer: FramedWrite::new(write_half, MessageCodec::with_max_frame_size(limits.max_frame_size)),
+            limits,
+            literal_bytes: 0,
+            file_size: 0,
         }
     }
 
     pub async fn send(&mut self, message: Message) -> crate::Result<()> {
-        let serialized = rmp_serde::to_vec(&message)?;
-        self.write_half.write_all(&serialized).await?;
-        self.write_half.flush().await?;
-        trace!("Sent message: (size: {}): {:#?}", serialized.len(), message);
+        trace!("Sending message: {:#?}", message);
+        self.writer.send(message).await?;
         Ok(())
     }
 
@@ -46,8 +192,26 @@ impl Connection {
 
         loop {
             tokio::select! {
-                Some(message) = outbound_message_rx.recv() => {
-                    self.send(message).await?;
+                maybe_outbound = outbound_message_rx.recv() => {
+                    match maybe_outbound {
+                        Some(message) => {
+                            if let Err(e) = self.send(message).await {
+                                // The peer may have hung up on us because of
+                                // what we were sending (e.g. a frame too large
+                                // for it), in which case it told us why first.
+                                if matches!(e, SyncrError::IoError(_)) {
+                                    self.forward_remaining_messages().await;
+                                }
+                                return Err(e);
+                            }
+                        },
+                        None => {
+                            // Our side of the session is over, so there is
+                            // nothing left to say.
+                            trace!("Session ended, closing connection");
+                            return Ok(());
+                        }
+                    }
                 },
                 maybe_message = self.read_message() => {
                     match maybe_message {
@@ -57,12 +221,16 @@ impl Connection {
                         }
                         Err(e) => {
                             trace!("Error reading message: {}", e);
+                            self.report(&e).await;
                             return Err(e);
                         }
                         Ok(Some(message)) => {
-                            let msg_debug = format!("{:#?}", message);
+                            if let Err(e) = self.limits.check(&message, &mut self.literal_bytes, &mut self.file_size) {
+                                self.report(&e).await;
+                                return Err(e);
+                            }
+                            trace!("Received message: ({:#?})", message);
                             self.inbound_message_tx.send(message).await?;
-                            trace!("Received message: ({})", msg_debug);
                         }
                     }
                 },
@@ -70,51 +238,338 @@ impl Connection {
         }
     }
 
-    async fn read_message(&mut self) -> crate::Result<Option<Message>> {
-        loop {
-            if let Ok(message) = rmp_serde::from_slice(&self.buffer) {
-                // A bit dubious approach to get the total bytes deserialized to get the framing to work correctly.
-                let bytes_serialized = rmp_serde::encode::to_vec(&message)?.len();
-                self.buffer.advance(bytes_serialized);
+    /// Let the peer know why we're about to close the connection, if the
+    /// error is something they should hear about.
+    async fn report(&mut self, error: &SyncrError) {
+        if let Some(message) = error.to_message() {
+            if let Err(e) = self.send(message).await {
+                trace!("Failed to report error to peer: {}", e);
+            }
+        }
+    }
 
-                return Ok(Some(message));
+    /// Pass on whatever the peer sent before closing the connection.
+    async fn forward_remaining_messages(&mut self) {
+        while let Ok(Some(message)) = self.read_message().await {
+            trace!("Received message: ({:#?})", message);
+            if self.inbound_message_tx.send(message).await.is_err() {
+                break;
             }
+        }
+    }
 
-            if 0 == self.read_half.read_buf(&mut self.buffer).await? {
-                // The remote closed the connection. For this to be a clean
-                // shutdown, there should be no data in the read buffer. If
-                // there is, this means that the peer closed the socket while
-                // sending a frame.
-                if self.buffer.is_empty() {
-                    return Ok(None);
-                } else {
-                    return Err(crate::SyncrError::ConnectionResetByPeer);
-                }
+    async fn read_message(&mut self) -> crate::Result<Option<Message>> {
+        self.reader.next().await.transpose()
+    }
+}
+
+
+/// Frames each msgpack encoded [`Message`] with an explicit length header,
+/// so the receiving end knows exactly how many bytes to wait for before
+/// decoding it.
+#[derive(Debug)]
+pub struct MessageCodec {
+    /// Decodes incoming frames, up to the maximum frame size.
+    frames: LengthDelimitedCodec,
+    /// Encodes outgoing frames, only limited by the size of the header.
+    outgoing: LengthDelimitedCodec,
+    /// The caps on the number of entries in incoming messages.
+    limits: Limits,
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageCodec {
+    /// A codec capped at the default [`Limits`].
+    pub fn new() -> Self {
+        Self::with_limits(Limits::default())
+    }
+
+    /// A codec that rejects incoming frames larger than `max_frame_size`.
+    pub fn with_max_frame_size(max_frame_size: usize) -> Self {
+        Self::with_limits(Limits { max_frame_size, ..Default::default() })
+    }
+
+    /// A codec that rejects incoming frames larger than
+    /// [`Limits::max_frame_size`], and messages with more entries than the
+    /// limits allow.
+    pub fn with_limits(limits: Limits) -> Self {
+        Self {
+            frames: LengthDelimitedCodec::builder().max_frame_length(limits.max_frame_size).new_codec(),
+            outgoing: LengthDelimitedCodec::builder().max_frame_length(usize::MAX).new_codec(),
+            limits,
+        }
+    }
+}
+
+fn frame_error(frames: &LengthDelimitedCodec, error: std::io::Error) -> SyncrError {
+    if error.get_ref().is_some_and(|inner| inner.is::<LengthDelimitedCodecError>()) {
+        return SyncrError::FrameTooLarge(frames.max_frame_length());
+    }
+    error.into()
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = crate::SyncrError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<Message>> {
+        match self.frames.decode(src).map_err(|e| frame_error(&self.frames, e))? {
+            Some(frame) => Ok(Some(decode_message(&frame, &self.limits)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> crate::Result<Option<Message>> {
+        match self.decode(src)? {
+            Some(message) => Ok(Some(message)),
+            // The remote closed the connection. For this to be a clean
+            // shutdown, there should be no data in the read buffer. If
+            // there is, this means that the peer closed the socket while
+            // sending a frame.
+            None if src.is_empty() => Ok(None),
+            None => Err(crate::SyncrError::ConnectionResetByPeer),
+        }
+    }
+}
+
+thread_local! {
+    /// The limits of the message being decoded on this thread, so that the
+    /// number of entries is checked as they're deserialized rather than once
+    /// they've all been allocated.
+    static DECODING: Cell<Option<Limits>> = const { Cell::new(None) };
+    /// The `(kind, count, max)` of the entries that went over the limits.
+    static TOO_MANY_ENTRIES: Cell<Option<(&'static str, usize, usize)>> = const { Cell::new(None) };
+}
+
+fn decode_message(frame: &[u8], limits: &Limits) -> crate::Result<Message> {
+    DECODING.set(Some(*limits));
+    let message = rmp_serde::from_slice(frame);
+    DECODING.set(None);
+    match (message, TOO_MANY_ENTRIES.take()) {
+        (Err(_), Some((kind, count, max))) => Err(SyncrError::TooManyEntries { kind, count, max }),
+        (message, _) => Ok(message?),
+    }
+}
+
+/// The instructions of a message, up to [`Limits::max_instructions`].
+fn capped_instructions<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<Instruction>, D::Error> {
+    let max = DECODING.get().map_or(usize::MAX, |limits| limits.max_instructions);
+    deserializer.deserialize_seq(CappedSeq { kind: "Instructions", max, entries: PhantomData })
+}
+
+/// The weak checksums of block signatures, up to [`Limits::max_signatures`].
+fn capped_signatures<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u32>, D::Error> {
+    let max = DECODING.get().map_or(usize::MAX, |limits| limits.max_signatures);
+    deserializer.deserialize_seq(CappedSeq { kind: "BlockSignatures", max, entries: PhantomData })
+}
+
+/// Deserializes a sequence of at most `max` entries, giving up as soon as
+/// its header announces more.
+struct CappedSeq<T> {
+    kind: &'static str,
+    max: usize,
+    entries: PhantomData<T>,
+}
+
+impl<'de, T: Deserialize<'de>> Visitor<'de> for CappedSeq<T> {
+    type Value = Vec<T>;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(formatter, "a sequence of at most {} entries", self.max)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Vec<T>, A::Error> {
+        let too_many = |count| {
+            TOO_MANY_ENTRIES.set(Some((self.kind, count, self.max)));
+            A::Error::custom(format!("{} entries, more than the maximum of {}", count, self.max))
+        };
+        let count = seq.size_hint().unwrap_or(0);
+        if count > self.max {
+            return Err(too_many(count));
+        }
+        // The entries may not all be there, so don't trust the header with
+        // more than a few of them up front.
+        let mut entries = Vec::with_capacity(count.min(4096));
+        while let Some(entry) = seq.next_element()? {
+            if entries.len() == self.max {
+                return Err(too_many(entries.len() + 1));
             }
+            entries.push(entry);
         }
+        Ok(entries)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = crate::SyncrError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> crate::Result<()> {
+        let serialized = rmp_serde::to_vec(&message)?;
+        self.outgoing.encode(Bytes::from(serialized), dst).map_err(|e| frame_error(&self.outgoing, e))?;
+        Ok(())
     }
 }
 
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Message {
+    Hello(Hello),
+    /// A random nonce the receiver challenges the sender with, if it
+    /// requires authentication.
+    AuthChallenge(Vec<u8>),
+    /// The HMAC of the receiver's nonce, keyed by the user's secret.
+    AuthResponse {
+        user: String,
+        mac: Vec<u8>,
+    },
     FileName(String),
-    WeakChecksums(Vec<u32>),
-    StrongChecksumRequest(Vec<usize>),
-    StrongChecksums(Vec<(usize, u128)>),
-    Matches(Vec<(usize, usize)>),
-    Instructions(Vec<Instruction>),
+    BlockSignatures {
+        /// The checksum configuration the receiver picked for the session.
+        config: ChecksumConfig,
+        /// The checksums of the receiver's non-overlapping blocks.
+        signatures: Signatures,
+    },
+    /// A batch of the instructions to rebuild the sender's file, in order.
+    Instructions {
+        #[serde(deserialize_with = "capped_instructions")]
+        instructions: Vec<Instruction>,
+    },
+    /// Sent once all the instructions are.
+    EndOfInstructions {
+        /// The strong hash of the sender's whole file, used by the receiver
+        /// to verify the reconstructed file.
+        digest: u128,
+    },
+    WholeFileRequest,
+    /// Sent by the receiver once its file is in sync.
+    Done,
+    /// Sent right before closing the connection because of an error.
+    Error {
+        code: ErrorCode,
+        message: String,
+    },
+}
+
+
+/// The `(weak, strong)` checksums of the receiver's blocks, as sent over the
+/// wire.
+///
+/// The strong checksums are truncated to the agreed length and packed into a
+/// single byte string, so a block costs a few bytes rather than the full 16
+/// (plus msgpack's overhead for each of them).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Signatures {
+    #[serde(deserialize_with = "capped_signatures")]
+    weak: Vec<u32>,
+    /// The strong checksums, `strong_length` little-endian bytes each.
+    strong: Bytes,
+}
+
+impl Signatures {
+    /// Pack `signatures`, whose strong checksums are at most `strong_length`
+    /// bytes long.
+    pub fn pack(signatures: impl IntoIterator<Item=(u32, u128)>, strong_length: usize) -> Self {
+        let mut weak = vec![];
+        let mut strong = vec![];
+        for (weak_checksum, strong_checksum) in signatures {
+            weak.push(weak_checksum);
+            strong.extend_from_slice(&strong_checksum.to_le_bytes()[..strong_length]);
+        }
+        Self { weak, strong: strong.into() }
+    }
+
+    /// The `(weak, strong)` checksums, given the length of the strong ones.
+    pub fn unpack(&self, strong_length: usize) -> crate::Result<Vec<(u32, u128)>> {
+        if strong_length == 0
+            || strong_length > crate::strong_checksum::MAX_LENGTH
+            || self.strong.len() != self.weak.len() * strong_length
+        {
+            return Err(SyncrError::MalformedSignatures {
+                count: self.weak.len(),
+                strong_length,
+                bytes: self.strong.len(),
+            });
+        }
+        let strong = self.strong.chunks_exact(strong_length).map(|bytes| {
+            let mut checksum = [0u8; crate::strong_checksum::MAX_LENGTH];
+            checksum[..strong_length].copy_from_slice(bytes);
+            u128::from_le_bytes(checksum)
+        });
+        Ok(self.weak.iter().copied().zip(strong).collect())
+    }
+
+    /// The number of blocks.
+    pub fn len(&self) -> usize {
+        self.weak.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.weak.is_empty()
+    }
+}
+
+
+/// Why a peer gave up on the session.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// We received something that doesn't follow the protocol.
+    ProtocolViolation,
+    /// The peers couldn't agree on the parameters of the session.
+    Incompatible,
+    /// The peer sent more than we're willing to accept.
+    LimitExceeded,
+    /// The requested file doesn't exist.
+    NotFound,
+    /// The requested file can't be accessed.
+    PermissionDenied,
+    /// Reading or writing a file failed.
+    Io,
+    /// The reconstructed file doesn't match the sender's file.
+    VerificationFailed,
+    /// The sender couldn't prove who it is.
+    AuthenticationFailed,
+    /// The receiver is shutting down, so the session may be retried later.
+    Unavailable,
+    /// Anything else.
+    Internal,
+}
+
+impl ErrorCode {
+    /// The process exit code for a session that failed with this code.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            ErrorCode::Internal => 1,
+            ErrorCode::Incompatible => 2,
+            ErrorCode::NotFound => 3,
+            ErrorCode::PermissionDenied => 4,
+            ErrorCode::Io => 5,
+            ErrorCode::VerificationFailed => 6,
+            ErrorCode::LimitExceeded => 7,
+            ErrorCode::ProtocolViolation => 8,
+            ErrorCode::AuthenticationFailed => 9,
+            ErrorCode::Unavailable => 10,
+        }
+    }
 }
 
 impl Message {
-    pub fn kind(&self) -> &str {
+    pub fn kind(&self) -> &'static str {
         match self {
+            Message::Hello { .. } => "Hello",
+            Message::AuthChallenge(_) => "AuthChallenge",
+            Message::AuthResponse { .. } => "AuthResponse",
             Message::FileName { .. } => "FileName",
-            Message::WeakChecksums { .. } => "WeakChecksums",
-            Message::StrongChecksumRequest { .. } => "StrongChecksumRequest",
-            Message::StrongChecksums { .. } => "StrongChecksums",
-            Message::Matches { .. } => "Matches",
+            Message::BlockSignatures { .. } => "BlockSignatures",
             Message::Instructions { .. } => "Instructions",
+            Message::EndOfInstructions { .. } => "EndOfInstructions",
+            Message::WholeFileRequest => "WholeFileRequest",
+            Message::Done => "Done",
+            Message::Error { .. } => "Error",
         }
     }
 }
@@ -132,4 +587,259 @@ pub enum Instruction {
         length: usize,
         new_offset: usize,
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codec_round_trips_messages() {
+        let mut codec = MessageCodec::new();
+        let mut buffer = BytesMut::new();
+        codec.encode(Message::FileName("test.txt".into()), &mut buffer).unwrap();
+        codec.encode(Message::WholeFileRequest, &mut buffer).unwrap();
+
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(Message::FileName(name)) if name == "test.txt"));
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(Message::WholeFileRequest)));
+        assert!(codec.decode_eof(&mut buffer).unwrap().is_none());
+    }
+
+    #[test]
+    fn codec_waits_for_the_whole_frame() {
+        let mut codec = MessageCodec::new();
+        let mut encoded = BytesMut::new();
+        let signatures = Signatures::pack(vec![(1, 2); 100], 16);
+        codec.encode(Message::BlockSignatures { config: ChecksumConfig::default(), signatures }, &mut encoded).unwrap();
+
+        let mut buffer = encoded.split_to(encoded.len() / 2);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        assert!(matches!(codec.decode_eof(&mut buffer), Err(crate::SyncrError::ConnectionResetByPeer)));
+
+        buffer.unsplit(encoded);
+        assert!(matches!(codec.decode(&mut buffer).unwrap(), Some(Message::BlockSignatures { signatures, .. }) if signatures.len() == 100));
+    }
+
+    #[test]
+    fn codec_rejects_incoming_frames_over_the_limit() {
+        let mut buffer = BytesMut::new();
+        MessageCodec::new().encode(Message::FileName("a".repeat(100)), &mut buffer).unwrap();
+
+        let mut codec = MessageCodec::with_max_frame_size(64);
+        assert!(matches!(codec.decode(&mut buffer), Err(SyncrError::FrameTooLarge(64))));
+        // Only the peer's limit applies to what we send.
+        assert!(codec.encode(Message::FileName("a".repeat(100)), &mut BytesMut::new()).is_ok());
+    }
+
+    #[test]
+    fn codec_caps_entries_while_decoding() {
+        let mut buffer = BytesMut::new();
+        let instructions = (0..3).map(|i| Instruction::Replicate { from_offset: 0, length: 1, new_offset: i }).collect();
+        MessageCodec::new().encode(Message::Instructions { instructions }, &mut buffer).unwrap();
+        let signatures = Signatures::pack(vec![(0, 0); 3], 2);
+        MessageCodec::new().encode(Message::BlockSignatures { config: ChecksumConfig::default(), signatures }, &mut buffer).unwrap();
+
+        let mut codec = MessageCodec::with_limits(Limits { max_instructions: 2, max_signatures: 2, ..Default::default() });
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(SyncrError::TooManyEntries { kind: "Instructions", count: 3, max: 2 })
+        ));
+        assert!(matches!(
+            codec.decode(&mut buffer),
+            Err(SyncrError::TooManyEntries { kind: "BlockSignatures", count: 3, max: 2 })
+        ));
+
+        // A header announcing more entries than the frame could hold is
+        // rejected before anything is allocated for them.
+        let mut frame = vec![0x81, 0xac];
+        frame.extend_from_slice(b"Instructions");
+        frame.extend_from_slice(&[0x91, 0xdd, 0xff, 0xff, 0xff, 0xff]);
+        let mut buffer = BytesMut::new();
+        LengthDelimitedCodec::new().encode(Bytes::from(frame), &mut buffer).unwrap();
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buffer),
+            Err(SyncrError::TooManyEntries { kind: "Instructions", count: 0xffff_ffff, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn instructions_just_over_the_frame_size_are_rejected() {
+        let instructions = || Message::Instructions {
+            instructions: vec![Instruction::NewData { offset: 0, length: 4096, bytes: vec![7; 4096] }],
+        };
+        let mut encoded = BytesMut::new();
+        MessageCodec::new().encode(instructions(), &mut encoded).unwrap();
+        // Without the 4 bytes of the length header.
+        let frame_size = encoded.len() - 4;
+
+        for (max_frame_size, accepted) in [(frame_size, true), (frame_size - 1, false)] {
+            let (client, server) = tokio::io::duplex(1024);
+
+            let (client_inbound_tx, mut client_inbound_rx) = tokio::sync::mpsc::channel(10);
+            let (client_outbound_tx, client_outbound_rx) = tokio::sync::mpsc::channel(10);
+            // The sender's own limit is lower than the frame, but that doesn't
+            // stop it from sending it.
+            let limits = Limits { max_frame_size: 1024, ..Default::default() };
+            let client = Connection::from_stream(client, client_inbound_tx, limits);
+
+            let (server_inbound_tx, mut server_inbound_rx) = tokio::sync::mpsc::channel(10);
+            let (_server_outbound_tx, server_outbound_rx) = tokio::sync::mpsc::channel(10);
+            let limits = Limits { max_frame_size, ..Default::default() };
+            let server = Connection::from_stream(server, server_inbound_tx, limits);
+
+            let client = tokio::spawn(client.run(client_outbound_rx));
+            let server = tokio::spawn(server.run(server_outbound_rx));
+            client_outbound_tx.send(instructions()).await.unwrap();
+
+            if accepted {
+                assert!(matches!(server_inbound_rx.recv().await, Some(Message::Instructions { .. })));
+                drop(client_outbound_tx);
+                server.await.unwrap().unwrap();
+                client.await.unwrap().unwrap();
+            } else {
+                assert!(matches!(server.await.unwrap(), Err(SyncrError::FrameTooLarge(max)) if max == max_frame_size));
+                // The sender hears why, even though the receiver hung up
+                // before reading the whole frame.
+                assert!(client.await.unwrap().is_err());
+                assert!(matches!(
+                    client_inbound_rx.recv().await,
+                    Some(Message::Error { code: ErrorCode::LimitExceeded, .. })
+                ));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connections_talk_over_any_transport() {
+        let (client, server) = tokio::io::duplex(64);
+
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(10);
+        let (client_outbound_tx, client_outbound_rx) = tokio::sync::mpsc::channel(10);
+        let client = Connection::from_stream(client, client_inbound_tx, Limits::default());
+
+        let (server_inbound_tx, mut server_inbound_rx) = tokio::sync::mpsc::channel(10);
+        let (_server_outbound_tx, server_outbound_rx) = tokio::sync::mpsc::channel(10);
+        let server = Connection::from_stream(server, server_inbound_tx, Limits::default());
+
+        let client = tokio::spawn(client.run(client_outbound_rx));
+        let server = tokio::spawn(server.run(server_outbound_rx));
+
+        // Larger than the duplex buffer, so it takes several reads.
+        client_outbound_tx.send(Message::FileName("a".repeat(1000))).await.unwrap();
+        assert!(matches!(server_inbound_rx.recv().await, Some(Message::FileName(name)) if name.len() == 1000));
+
+        // Hanging up on our side closes the connection for both.
+        drop(client_outbound_tx);
+        client.await.unwrap().unwrap();
+        server.await.unwrap().unwrap();
+        assert!(server_inbound_rx.recv().await.is_none());
+    }
+
+    #[test]
+    fn errors_map_to_codes() {
+        let not_found = SyncrError::IoError(std::io::Error::from(std::io::ErrorKind::NotFound));
+        assert_eq!(not_found.code(), ErrorCode::NotFound);
+        assert!(matches!(
+            not_found.to_message(),
+            Some(Message::Error { code: ErrorCode::NotFound, .. })
+        ));
+
+        assert_eq!(SyncrError::FrameTooLarge(1).code(), ErrorCode::LimitExceeded);
+        assert_eq!(SyncrError::TimedOut(std::time::Duration::from_secs(1)).code(), ErrorCode::LimitExceeded);
+        assert_eq!(SyncrError::UnexpectedMessage { kind: "FileName", state: "Done" }.code(), ErrorCode::ProtocolViolation);
+        assert_eq!(SyncrError::AuthenticationFailed("alice".into()).code(), ErrorCode::AuthenticationFailed);
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::ExitStatusExt;
+            let failed = SyncrError::RemoteShellFailed { command: "ssh host".into(), status: std::process::ExitStatus::from_raw(1 << 8) };
+            assert_eq!(failed.code(), ErrorCode::Io);
+            assert_eq!(failed.to_string(), "Remote shell \"ssh host\" exit status: 1.");
+        }
+
+        let remote = SyncrError::Remote { code: ErrorCode::Incompatible, message: String::new() };
+        assert_eq!(remote.exit_code(), ErrorCode::Incompatible.exit_code());
+        assert!(remote.to_message().is_none());
+    }
+
+    #[test]
+    fn limits_cap_entries_and_literal_bytes() {
+        let limits = Limits {
+            max_signatures: 2,
+            max_literal_bytes: 10,
+            ..Default::default()
+        };
+        let mut literal_bytes = 0;
+        let mut file_size = 0;
+
+        let signatures = Message::BlockSignatures { config: ChecksumConfig::default(), signatures: Signatures::pack(vec![(0, 0); 3], 2) };
+        assert!(matches!(
+            limits.check(&signatures, &mut literal_bytes, &mut file_size),
+            Err(SyncrError::TooManyEntries { kind: "BlockSignatures", count: 3, max: 2 })
+        ));
+
+        let instructions = Message::Instructions {
+            instructions: vec![Instruction::NewData { offset: 0, length: 6, bytes: vec![0; 6] }],
+        };
+        limits.check(&instructions, &mut literal_bytes, &mut file_size).unwrap();
+        assert_eq!(literal_bytes, 6);
+        assert!(matches!(
+            limits.check(&instructions, &mut literal_bytes, &mut file_size),
+            Err(SyncrError::TooManyLiteralBytes(10))
+        ));
+    }
+
+    #[test]
+    fn file_size_adds_up_across_batches() {
+        let limits = Limits { max_file_size: 10, ..Default::default() };
+        let (mut literal_bytes, mut file_size) = (0, 0);
+        let batch = |new_offset| Message::Instructions {
+            instructions: vec![Instruction::Replicate { from_offset: 0, length: 6, new_offset }],
+        };
+
+        limits.check(&batch(0), &mut literal_bytes, &mut file_size).unwrap();
+        assert!(matches!(
+            limits.check(&batch(6), &mut literal_bytes, &mut file_size),
+            Err(SyncrError::FileTooLarge(10))
+        ));
+
+        // A whole-file transfer starts over.
+        let (mut literal_bytes, mut file_size) = (0, 0);
+        limits.check(&batch(0), &mut literal_bytes, &mut file_size).unwrap();
+        limits.check(&Message::EndOfInstructions { digest: 0 }, &mut literal_bytes, &mut file_size).unwrap();
+        limits.check(&batch(0), &mut literal_bytes, &mut file_size).unwrap();
+    }
+
+    #[test]
+    fn frames_must_fit_a_batch_of_instructions() {
+        assert!(Limits::default().validate().is_ok());
+        let limits = Limits { max_frame_size: MIN_FRAME_SIZE - 1, ..Default::default() };
+        assert!(matches!(limits.validate(), Err(SyncrError::InvalidConfig { .. })));
+    }
+
+    #[test]
+    fn signatures_carry_truncated_strong_checksums() {
+        let signatures = vec![(1, 0x02_0100), (u32::MAX, 0x0203)];
+        let packed = Signatures::pack(signatures.clone(), 3);
+        assert_eq!(packed.len(), 2);
+        assert_eq!(packed.unpack(3).unwrap(), signatures);
+
+        assert!(matches!(
+            packed.unpack(2),
+            Err(SyncrError::MalformedSignatures { count: 2, strong_length: 2, bytes: 6 })
+        ));
+        assert!(packed.unpack(0).is_err());
+        assert!(Signatures::default().unpack(16).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_signatures_are_smaller() {
+        let encoded_size = |strong_length| {
+            let signatures = Signatures::pack((0..1000).map(|i| (i, u128::MAX >> (8 * (16 - strong_length)))), strong_length);
+            let mut buffer = BytesMut::new();
+            MessageCodec::new().encode(Message::BlockSignatures { config: ChecksumConfig::default(), signatures }, &mut buffer).unwrap();
+            buffer.len()
+        };
+        assert!(encoded_size(2) * 2 < encoded_size(16));
+    }
+}
@@ -0,0 +1,349 @@
+//! The rolling hashes behind the weak checksums.
+//!
+//! A rolling hash can slide its window over the data one byte at a time,
+//! updating the checksum from the byte leaving the window and the byte
+//! entering it instead of hashing the whole window again. That's what lets
+//! the sender look for the receiver's blocks at every offset of its file.
+//!
+//! Besides rsync's own checksum, any of [`Adler32`], [`Buzhash`] and
+//! [`RabinKarp`] may be used (see [`RollingHashAlgorithm`]). All of them are
+//! always built in.
+
+use serde::{Serialize, Deserialize};
+
+
+/// A hash over a window of data that can be rolled forward one byte at a
+/// time.
+pub trait RollingHash {
+    /// What's carried over from one window to the next.
+    type State: Copy;
+
+    /// The state of `window`, computed from scratch.
+    fn init(&self, window: &[u8]) -> Self::State;
+
+    /// Slide a window of `length` bytes forward by one byte: `outgoing` is
+    /// the first byte of the window, and `incoming` the byte right after it.
+    fn roll(&self, state: Self::State, length: usize, outgoing: u8, incoming: u8) -> Self::State;
+
+    /// The checksum of a window, given its state.
+    fn checksum(&self, state: &Self::State) -> u32;
+
+    /// The number of bits the checksums are spread over.
+    fn bits(&self) -> u32 {
+        u32::BITS
+    }
+
+    /// The checksum of `window`, computed from scratch.
+    fn hash(&self, window: &[u8]) -> u32 {
+        self.checksum(&self.init(window))
+    }
+}
+
+
+/// The checksum from the rsync paper: the sum `a` of the bytes, and the sum
+/// `b` of the prefix sums, both modulo `modulus`, packed as `a + (b << 16)`.
+#[derive(Debug, Copy, Clone)]
+pub struct Rsync {
+    pub modulus: u32,
+}
+
+impl RollingHash for Rsync {
+    type State = (u32, u32);
+
+    fn init(&self, window: &[u8]) -> Self::State {
+        let modulus = self.modulus as u64;
+        window.iter().fold((0, 0), |(a, b), &byte| {
+            let a = (a as u64 + byte as u64) % modulus;
+            (a as u32, ((b as u64 + a) % modulus) as u32)
+        })
+    }
+
+    fn roll(&self, (a, b): Self::State, length: usize, outgoing: u8, incoming: u8) -> Self::State {
+        let modulus = self.modulus as i64;
+        let a = (a as i64 - outgoing as i64 + incoming as i64).rem_euclid(modulus);
+        let b = (b as i64 - (length as i64 % modulus) * outgoing as i64 + a).rem_euclid(modulus);
+        (a as u32, b as u32)
+    }
+
+    fn checksum(&self, &(a, b): &Self::State) -> u32 {
+        a.wrapping_add(b << 16)
+    }
+
+    fn bits(&self) -> u32 {
+        2 * self.modulus.checked_ilog2().unwrap_or(0)
+    }
+}
+
+
+/// Adler-32, as used by zlib: like [`Rsync`], but modulo the largest prime
+/// below 2^16, and with `a` starting at 1 so runs of zeros of different
+/// lengths have different checksums.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Adler32;
+
+impl Adler32 {
+    pub const MODULUS: u32 = 65521;
+}
+
+impl RollingHash for Adler32 {
+    type State = (u32, u32);
+
+    fn init(&self, window: &[u8]) -> Self::State {
+        window.iter().fold((1, 0), |(a, b), &byte| {
+            let a = (a + byte as u32) % Self::MODULUS;
+            (a, (b + a) % Self::MODULUS)
+        })
+    }
+
+    fn roll(&self, (a, b): Self::State, length: usize, outgoing: u8, incoming: u8) -> Self::State {
+        let modulus = Self::MODULUS as i64;
+        let a = (a as i64 - outgoing as i64 + incoming as i64).rem_euclid(modulus);
+        let b = (b as i64 - (length as i64 % modulus) * outgoing as i64 + a - 1).rem_euclid(modulus);
+        (a as u32, b as u32)
+    }
+
+    fn checksum(&self, &(a, b): &Self::State) -> u32 {
+        (b << 16) | a
+    }
+
+    fn bits(&self) -> u32 {
+        2 * Self::MODULUS.ilog2()
+    }
+}
+
+
+/// A cyclic polynomial hash: every byte is mapped to a random word, rotated
+/// by its distance from the end of the window, and XORed with the others.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Buzhash;
+
+impl Buzhash {
+    /// The random word of every byte.
+    pub const TABLE: [u32; 256] = buzhash_table();
+}
+
+/// Fill the table with splitmix64, so it's the same in every build.
+const fn buzhash_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut seed: u64 = 0x5359_4e43_5220_4255;
+    let mut i = 0;
+    while i < table.len() {
+        seed = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = ((z ^ (z >> 31)) >> 32) as u32;
+        i += 1;
+    }
+    table
+}
+
+impl RollingHash for Buzhash {
+    type State = u32;
+
+    fn init(&self, window: &[u8]) -> Self::State {
+        window.iter().fold(0, |hash: u32, &byte| hash.rotate_left(1) ^ Self::TABLE[byte as usize])
+    }
+
+    fn roll(&self, hash: Self::State, length: usize, outgoing: u8, incoming: u8) -> Self::State {
+        let outgoing = Self::TABLE[outgoing as usize].rotate_left((length % u32::BITS as usize) as u32);
+        hash.rotate_left(1) ^ outgoing ^ Self::TABLE[incoming as usize]
+    }
+
+    fn checksum(&self, hash: &Self::State) -> u32 {
+        *hash
+    }
+}
+
+
+/// The Rabin-Karp polynomial hash: the bytes of the window are the
+/// coefficients of a polynomial evaluated at [`RabinKarp::BASE`], modulo the
+/// Mersenne prime 2^61 - 1.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RabinKarp;
+
+impl RabinKarp {
+    pub const MODULUS: u64 = (1 << 61) - 1;
+    pub const BASE: u64 = 0x100_0000_01b3;
+
+    fn multiply(x: u64, y: u64) -> u64 {
+        ((x as u128 * y as u128) % Self::MODULUS as u128) as u64
+    }
+}
+
+/// The hash of a window, and the weight of its first byte.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RabinKarpState {
+    hash: u64,
+    power: u64,
+}
+
+impl RollingHash for RabinKarp {
+    type State = RabinKarpState;
+
+    fn init(&self, window: &[u8]) -> Self::State {
+        let hash = window.iter().fold(0, |hash, &byte| (Self::multiply(hash, Self::BASE) + byte as u64) % Self::MODULUS);
+        let power = (1..window.len()).fold(1, |power, _| Self::multiply(power, Self::BASE));
+        RabinKarpState { hash, power }
+    }
+
+    fn roll(&self, state: Self::State, _length: usize, outgoing: u8, incoming: u8) -> Self::State {
+        let hash = (state.hash + Self::MODULUS - Self::multiply(outgoing as u64, state.power)) % Self::MODULUS;
+        RabinKarpState {
+            hash: (Self::multiply(hash, Self::BASE) + incoming as u64) % Self::MODULUS,
+            power: state.power,
+        }
+    }
+
+    fn checksum(&self, state: &Self::State) -> u32 {
+        (state.hash ^ (state.hash >> 32)) as u32
+    }
+}
+
+
+/// One of the rolling hashes, as picked in a [`crate::ChecksumConfig`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+pub enum RollingHashAlgorithm {
+    #[default]
+    #[serde(rename = "rsync")]
+    #[value(name = "rsync")]
+    Rsync,
+    #[serde(rename = "adler32")]
+    #[value(name = "adler32")]
+    Adler32,
+    #[serde(rename = "buzhash")]
+    #[value(name = "buzhash")]
+    Buzhash,
+    #[serde(rename = "rabin-karp")]
+    #[value(name = "rabin-karp")]
+    RabinKarp,
+}
+
+impl RollingHashAlgorithm {
+    pub const ALL: &'static [Self] = &[Self::Rsync, Self::Adler32, Self::Buzhash, Self::RabinKarp];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Rsync => "rsync",
+            Self::Adler32 => "adler32",
+            Self::Buzhash => "buzhash",
+            Self::RabinKarp => "rabin-karp",
+        }
+    }
+}
+
+impl std::fmt::Display for RollingHashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+
+/// The checksums of every window of `block_size` bytes of some data, or the
+/// single checksum of data shorter than a block.
+#[derive(Debug)]
+pub struct RollingChecksums<'buf, H: RollingHash> {
+    hash: H,
+    data: &'buf [u8],
+    window: usize,
+    /// The start of the next window.
+    offset: usize,
+    state: Option<H::State>,
+}
+
+impl<'buf, H: RollingHash> RollingChecksums<'buf, H> {
+    pub fn new(hash: H, data: &'buf [u8], block_size: usize) -> Self {
+        Self {
+            hash,
+            data,
+            window: block_size.min(data.len()),
+            offset: 0,
+            state: None,
+        }
+    }
+}
+
+impl<H: RollingHash> Iterator for RollingChecksums<'_, H> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let end = self.offset + self.window;
+        if self.window == 0 || end > self.data.len() {
+            return None;
+        }
+        let state = match self.state {
+            None => self.hash.init(&self.data[..end]),
+            Some(state) => self.hash.roll(state, self.window, self.data[self.offset - 1], self.data[end - 1]),
+        };
+        self.state = Some(state);
+        self.offset += 1;
+        Some(self.hash.checksum(&state))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Every window's checksum, each computed from scratch.
+    fn recomputed<H: RollingHash>(hash: &H, data: &[u8], block_size: usize) -> Vec<u32> {
+        if data.len() < block_size {
+            return data.first().map(|_| hash.hash(data)).into_iter().collect();
+        }
+        data.windows(block_size).map(|window| hash.hash(window)).collect()
+    }
+
+    #[test]
+    fn adler32_matches_the_reference() {
+        assert_eq!(Adler32.hash(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(Adler32.hash(b""), 1);
+    }
+
+    #[test]
+    fn rsync_matches_the_expanded_sums() {
+        let data: Vec<u8> = (0..=255).collect();
+        let modulus = 1 << 16;
+        let expected = crate::weak_checksum::WeakCheckSum::a_expanded(modulus, 10, 109, &data)
+            + (crate::weak_checksum::WeakCheckSum::b_expanded(modulus, 10, 109, &data) << 16);
+        assert_eq!(Rsync { modulus }.hash(&data[10..110]), expected);
+    }
+
+    #[test]
+    fn every_algorithm_has_a_distinct_name() {
+        let mut names: Vec<_> = RollingHashAlgorithm::ALL.iter().map(|algorithm| algorithm.name()).collect();
+        names.sort();
+        names.dedup();
+        assert_eq!(names.len(), RollingHashAlgorithm::ALL.len());
+    }
+
+    #[test]
+    fn empty_data_has_no_checksums() {
+        assert_eq!(RollingChecksums::new(Buzhash, b"", 4).count(), 0);
+        assert_eq!(RollingChecksums::new(Buzhash, b"abc", 4).count(), 1);
+        assert_eq!(RollingChecksums::new(Buzhash, b"abcdef", 4).count(), 3);
+    }
+
+    proptest! {
+        #[test]
+        fn rolling_matches_recomputing(
+            data in prop::collection::vec(0u8..=255, 0..=1000),
+            block_size in 1usize..=100,
+            modulus in 1u32..=1 << 16,
+        ) {
+            let rsync = Rsync { modulus };
+            prop_assert_eq!(RollingChecksums::new(rsync, &data, block_size).collect::<Vec<_>>(), recomputed(&rsync, &data, block_size));
+            prop_assert_eq!(RollingChecksums::new(Adler32, &data, block_size).collect::<Vec<_>>(), recomputed(&Adler32, &data, block_size));
+            prop_assert_eq!(RollingChecksums::new(Buzhash, &data, block_size).collect::<Vec<_>>(), recomputed(&Buzhash, &data, block_size));
+            prop_assert_eq!(RollingChecksums::new(RabinKarp, &data, block_size).collect::<Vec<_>>(), recomputed(&RabinKarp, &data, block_size));
+        }
+
+        #[test]
+        fn long_windows_roll_too(data in prop::collection::vec(0u8..=255, 200..=400), block_size in 32usize..=200) {
+            // Windows longer than a word make buzhash rotate all the way around.
+            prop_assert_eq!(RollingChecksums::new(Buzhash, &data, block_size).collect::<Vec<_>>(), recomputed(&Buzhash, &data, block_size));
+            prop_assert_eq!(RollingChecksums::new(RabinKarp, &data, block_size).collect::<Vec<_>>(), recomputed(&RabinKarp, &data, block_size));
+        }
+    }
+}
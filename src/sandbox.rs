@@ -0,0 +1,123 @@
+//! Confining the files a client may touch to the directory served by the
+//! daemon.
+//!
+//! Clients name files relative to the served root. Absolute paths, `..`
+//! components and symlinks that lead outside of the root are all rejected
+//! with [`SyncrError::PathOutsideRoot`].
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::SyncrError;
+
+
+/// The directory a daemon serves files from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Root {
+    path: PathBuf,
+}
+
+impl Root {
+    pub fn new<P: AsRef<Path>>(path: P) -> crate::Result<Self> {
+        Ok(Self {
+            path: path.as_ref().canonicalize()?,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Resolve a path requested by a client to a file inside of the root.
+    ///
+    /// The parent directory of the file must exist. The file itself may not
+    /// exist yet, but if it does (and it's a symlink) it must resolve to a
+    /// file inside of the root too.
+    pub fn resolve(&self, requested: &str) -> crate::Result<PathBuf> {
+        let outside = || SyncrError::PathOutsideRoot(requested.to_string());
+
+        let mut relative = PathBuf::new();
+        for component in Path::new(requested).components() {
+            match component {
+                Component::Normal(name) => relative.push(name),
+                Component::CurDir => {},
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => return Err(outside()),
+            }
+        }
+        let file_name = relative.file_name().ok_or_else(outside)?.to_owned();
+
+        // Follow any symlinks on the way to the file.
+        let parent = self.path.join(&relative).parent().ok_or_else(outside)?.canonicalize()?;
+        if !parent.starts_with(&self.path) {
+            return Err(outside());
+        }
+
+        let path = parent.join(file_name);
+        match path.canonicalize() {
+            Ok(target) if !target.starts_with(&self.path) => Err(outside()),
+            Ok(_) => Ok(path),
+            // A dangling symlink would make us create a file outside of the
+            // root, so only accept files that don't exist at all.
+            Err(_) if path.symlink_metadata().is_ok() => Err(outside()),
+            Err(_) => Ok(path),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("syncr-sandbox-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(directory.join("root/sub")).unwrap();
+        std::fs::write(directory.join("root/sub/file.txt"), b"inside").unwrap();
+        std::fs::write(directory.join("secret.txt"), b"outside").unwrap();
+        directory
+    }
+
+    #[test]
+    fn relative_paths_resolve_inside_the_root() {
+        let directory = directory("relative");
+        let root = Root::new(directory.join("root")).unwrap();
+
+        assert_eq!(root.resolve("sub/file.txt").unwrap(), root.path().join("sub/file.txt"));
+        assert_eq!(root.resolve("./sub/./new.txt").unwrap(), root.path().join("sub/new.txt"));
+        assert!(matches!(root.resolve("missing/file.txt"), Err(SyncrError::IoError(_))));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn escapes_are_rejected() {
+        let directory = directory("escapes");
+        let root = Root::new(directory.join("root")).unwrap();
+
+        for requested in ["../secret.txt", "sub/../../secret.txt", "sub/../file.txt", "/etc/passwd", "", "."] {
+            assert!(
+                matches!(root.resolve(requested), Err(SyncrError::PathOutsideRoot(_))),
+                "{:?} should be rejected",
+                requested
+            );
+        }
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlinks_may_not_lead_outside_the_root() {
+        use std::os::unix::fs::symlink;
+
+        let directory = directory("symlinks");
+        let root = Root::new(directory.join("root")).unwrap();
+        symlink(directory.join("secret.txt"), directory.join("root/file-link")).unwrap();
+        symlink(&directory, directory.join("root/dir-link")).unwrap();
+        symlink(directory.join("missing.txt"), directory.join("root/dangling-link")).unwrap();
+        symlink(directory.join("root/sub"), directory.join("root/inside-link")).unwrap();
+
+        assert!(matches!(root.resolve("file-link"), Err(SyncrError::PathOutsideRoot(_))));
+        assert!(matches!(root.resolve("dir-link/secret.txt"), Err(SyncrError::PathOutsideRoot(_))));
+        assert!(matches!(root.resolve("dangling-link"), Err(SyncrError::PathOutsideRoot(_))));
+        assert_eq!(root.resolve("inside-link/file.txt").unwrap(), root.path().join("sub/file.txt"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,315 @@
+//! The states both ends of a session go through.
+//!
+//! The sender (`syncr`) and the receiver (`syncrd`) each keep track of where
+//! they are in the protocol, so that out-of-order or duplicate messages are
+//! rejected with a protocol error instead of being silently dropped.
+//!
+//! ```text
+//! Sender:   AwaitingHello -> [AwaitingChallenge ->] AwaitingSignatures -> AwaitingResult -> Done
+//! Receiver: AwaitingHello -> [AwaitingAuthResponse ->] AwaitingFileName -> AwaitingInstructions -> Applying -> Done
+//! ```
+//!
+//! The authentication step only happens if both peers agreed on it during
+//! the handshake. The receiver stays in `AwaitingInstructions` for as many
+//! batches of `Instructions` as the sender streams, until `EndOfInstructions`.
+//!
+//! If the reconstructed file fails verification, the receiver goes back from
+//! `Applying` to `AwaitingInstructions` (once) to receive the whole file.
+
+use crate::network::Message;
+use crate::SyncrError;
+
+
+/// Where the sending end of a session is in the protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SenderState {
+    #[default]
+    AwaitingHello,
+    AwaitingChallenge,
+    AwaitingSignatures,
+    AwaitingResult {
+        whole_file_sent: bool,
+    },
+    Done,
+}
+
+impl SenderState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SenderState::AwaitingHello => "AwaitingHello",
+            SenderState::AwaitingChallenge => "AwaitingChallenge",
+            SenderState::AwaitingSignatures => "AwaitingSignatures",
+            SenderState::AwaitingResult { .. } => "AwaitingResult",
+            SenderState::Done => "Done",
+        }
+    }
+
+    /// Check that `message` is expected in the current state and move on to
+    /// the next one.
+    pub fn receive(&mut self, message: &Message) -> crate::Result<()> {
+        *self = match (*self, message) {
+            (SenderState::Done, _) => return Err(unexpected(message, self.name())),
+            (_, Message::Error { .. }) => SenderState::Done,
+            (SenderState::AwaitingHello, Message::Hello(_)) => SenderState::AwaitingSignatures,
+            (SenderState::AwaitingChallenge, Message::AuthChallenge(_)) => SenderState::AwaitingSignatures,
+            (SenderState::AwaitingSignatures, Message::BlockSignatures { .. }) => {
+                SenderState::AwaitingResult { whole_file_sent: false }
+            },
+            (SenderState::AwaitingResult { whole_file_sent: false }, Message::WholeFileRequest) => {
+                SenderState::AwaitingResult { whole_file_sent: true }
+            },
+            (SenderState::AwaitingResult { .. }, Message::Done) => SenderState::Done,
+            _ => return Err(unexpected(message, self.name())),
+        };
+        Ok(())
+    }
+
+    /// The receiver requires us to authenticate before anything else.
+    pub fn expect_challenge(&mut self) {
+        debug_assert_eq!(*self, SenderState::AwaitingSignatures);
+        *self = SenderState::AwaitingChallenge;
+    }
+
+    /// Check that it's fine for the connection to be closed now.
+    pub fn closed(&self) -> crate::Result<()> {
+        match self {
+            SenderState::Done => Ok(()),
+            _ => Err(SyncrError::UnexpectedEndOfSession(self.name())),
+        }
+    }
+}
+
+
+/// Where the receiving end of a session is in the protocol.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum ReceiverState {
+    #[default]
+    AwaitingHello,
+    AwaitingAuthResponse,
+    AwaitingFileName,
+    AwaitingInstructions {
+        whole_file: bool,
+    },
+    Applying {
+        whole_file: bool,
+    },
+    Done,
+}
+
+impl ReceiverState {
+    pub fn name(&self) -> &'static str {
+        match self {
+            ReceiverState::AwaitingHello => "AwaitingHello",
+            ReceiverState::AwaitingAuthResponse => "AwaitingAuthResponse",
+            ReceiverState::AwaitingFileName => "AwaitingFileName",
+            ReceiverState::AwaitingInstructions { .. } => "AwaitingInstructions",
+            ReceiverState::Applying { .. } => "Applying",
+            ReceiverState::Done => "Done",
+        }
+    }
+
+    /// Check that `message` is expected in the current state and move on to
+    /// the next one.
+    pub fn receive(&mut self, message: &Message) -> crate::Result<()> {
+        *self = match (*self, message) {
+            (ReceiverState::Done, _) => return Err(unexpected(message, self.name())),
+            (_, Message::Error { .. }) => ReceiverState::Done,
+            (ReceiverState::AwaitingHello, Message::Hello(_)) => ReceiverState::AwaitingFileName,
+            (ReceiverState::AwaitingAuthResponse, Message::AuthResponse { .. }) => ReceiverState::AwaitingFileName,
+            (ReceiverState::AwaitingFileName, Message::FileName(_)) => {
+                ReceiverState::AwaitingInstructions { whole_file: false }
+            },
+            (ReceiverState::AwaitingInstructions { whole_file }, Message::Instructions { .. }) => {
+                ReceiverState::AwaitingInstructions { whole_file }
+            },
+            (ReceiverState::AwaitingInstructions { whole_file }, Message::EndOfInstructions { .. }) => {
+                ReceiverState::Applying { whole_file }
+            },
+            _ => return Err(unexpected(message, self.name())),
+        };
+        Ok(())
+    }
+
+    /// We require the sender to authenticate before naming a file.
+    pub fn expect_authentication(&mut self) {
+        debug_assert_eq!(*self, ReceiverState::AwaitingFileName);
+        *self = ReceiverState::AwaitingAuthResponse;
+    }
+
+    /// The instructions were applied and the file is in sync.
+    pub fn applied(&mut self) {
+        debug_assert!(matches!(self, ReceiverState::Applying { .. }));
+        *self = ReceiverState::Done;
+    }
+
+    /// Go back to waiting for instructions, this time for the whole file,
+    /// after the delta failed verification.
+    ///
+    /// Returns `false` if the whole file was already requested, in which case
+    /// there is nothing left to try.
+    pub fn fall_back_to_whole_file(&mut self) -> bool {
+        match self {
+            ReceiverState::Applying { whole_file: false } => {
+                *self = ReceiverState::AwaitingInstructions { whole_file: true };
+                true
+            },
+            _ => false,
+        }
+    }
+
+    /// Check that it's fine for the connection to be closed now.
+    pub fn closed(&self) -> crate::Result<()> {
+        match self {
+            ReceiverState::Done => Ok(()),
+            _ => Err(SyncrError::UnexpectedEndOfSession(self.name())),
+        }
+    }
+}
+
+
+fn unexpected(message: &Message, state: &'static str) -> SyncrError {
+    SyncrError::UnexpectedMessage {
+        kind: message.kind(),
+        state,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handshake::Hello;
+    use crate::network::Signatures;
+    use crate::ChecksumConfig;
+
+    fn hello() -> Message {
+        Message::Hello(Hello::new(None, None))
+    }
+
+    fn signatures() -> Message {
+        Message::BlockSignatures { config: ChecksumConfig::default(), signatures: Signatures::default() }
+    }
+
+    fn instructions() -> Message {
+        Message::Instructions { instructions: vec![] }
+    }
+
+    fn end_of_instructions() -> Message {
+        Message::EndOfInstructions { digest: 0 }
+    }
+
+    #[test]
+    fn sender_goes_through_a_whole_session() {
+        let mut state = SenderState::default();
+        state.receive(&hello()).unwrap();
+        assert_eq!(state, SenderState::AwaitingSignatures);
+        state.receive(&signatures()).unwrap();
+        assert_eq!(state, SenderState::AwaitingResult { whole_file_sent: false });
+        state.receive(&Message::WholeFileRequest).unwrap();
+        assert_eq!(state, SenderState::AwaitingResult { whole_file_sent: true });
+        assert!(state.closed().is_err());
+        state.receive(&Message::Done).unwrap();
+        assert_eq!(state, SenderState::Done);
+        assert!(state.closed().is_ok());
+    }
+
+    #[test]
+    fn sender_rejects_out_of_order_messages() {
+        let mut state = SenderState::default();
+        assert!(matches!(
+            state.receive(&signatures()),
+            Err(SyncrError::UnexpectedMessage { kind: "BlockSignatures", state: "AwaitingHello" })
+        ));
+
+        state.receive(&hello()).unwrap();
+        assert!(matches!(state.receive(&hello()), Err(SyncrError::UnexpectedMessage { .. })));
+        assert!(matches!(state.receive(&Message::FileName("a".into())), Err(SyncrError::UnexpectedMessage { .. })));
+    }
+
+    #[test]
+    fn sender_only_sends_the_whole_file_once() {
+        let mut state = SenderState::AwaitingResult { whole_file_sent: false };
+        state.receive(&Message::WholeFileRequest).unwrap();
+        assert!(matches!(state.receive(&Message::WholeFileRequest), Err(SyncrError::UnexpectedMessage { .. })));
+    }
+
+    #[test]
+    fn receiver_goes_through_a_whole_session() {
+        let mut state = ReceiverState::default();
+        state.receive(&hello()).unwrap();
+        assert_eq!(state, ReceiverState::AwaitingFileName);
+        state.receive(&Message::FileName("a".into())).unwrap();
+        assert_eq!(state, ReceiverState::AwaitingInstructions { whole_file: false });
+        state.receive(&instructions()).unwrap();
+        state.receive(&instructions()).unwrap();
+        assert_eq!(state, ReceiverState::AwaitingInstructions { whole_file: false });
+        state.receive(&end_of_instructions()).unwrap();
+        assert_eq!(state, ReceiverState::Applying { whole_file: false });
+        assert!(state.closed().is_err());
+        state.applied();
+        assert_eq!(state, ReceiverState::Done);
+        assert!(state.closed().is_ok());
+    }
+
+    #[test]
+    fn authentication_comes_before_the_file_name() {
+        let mut sender = SenderState::default();
+        sender.receive(&hello()).unwrap();
+        sender.expect_challenge();
+        assert!(matches!(sender.receive(&signatures()), Err(SyncrError::UnexpectedMessage { .. })));
+        sender.receive(&Message::AuthChallenge(vec![0; 32])).unwrap();
+        assert_eq!(sender, SenderState::AwaitingSignatures);
+
+        let mut receiver = ReceiverState::default();
+        receiver.receive(&hello()).unwrap();
+        receiver.expect_authentication();
+        assert!(matches!(
+            receiver.receive(&Message::FileName("a".into())),
+            Err(SyncrError::UnexpectedMessage { kind: "FileName", state: "AwaitingAuthResponse" })
+        ));
+        receiver.receive(&Message::AuthResponse { user: "alice".into(), mac: vec![] }).unwrap();
+        assert_eq!(receiver, ReceiverState::AwaitingFileName);
+        assert!(matches!(
+            receiver.receive(&Message::AuthResponse { user: "alice".into(), mac: vec![] }),
+            Err(SyncrError::UnexpectedMessage { .. })
+        ));
+    }
+
+    #[test]
+    fn receiver_falls_back_to_the_whole_file_once() {
+        let mut state = ReceiverState::Applying { whole_file: false };
+        assert!(state.fall_back_to_whole_file());
+        assert_eq!(state, ReceiverState::AwaitingInstructions { whole_file: true });
+        state.receive(&end_of_instructions()).unwrap();
+        assert!(!state.fall_back_to_whole_file());
+    }
+
+    #[test]
+    fn receiver_rejects_out_of_order_messages() {
+        let mut state = ReceiverState::default();
+        assert!(matches!(
+            state.receive(&Message::FileName("a".into())),
+            Err(SyncrError::UnexpectedMessage { kind: "FileName", state: "AwaitingHello" })
+        ));
+
+        state.receive(&hello()).unwrap();
+        assert!(matches!(state.receive(&instructions()), Err(SyncrError::UnexpectedMessage { .. })));
+        assert!(matches!(state.receive(&end_of_instructions()), Err(SyncrError::UnexpectedMessage { .. })));
+        state.receive(&Message::FileName("a".into())).unwrap();
+        assert!(matches!(state.receive(&Message::FileName("a".into())), Err(SyncrError::UnexpectedMessage { .. })));
+        assert!(matches!(state.receive(&Message::WholeFileRequest), Err(SyncrError::UnexpectedMessage { .. })));
+    }
+
+    #[test]
+    fn errors_end_the_session() {
+        let error = Message::Error { code: crate::network::ErrorCode::Internal, message: String::new() };
+
+        let mut sender = SenderState::AwaitingSignatures;
+        sender.receive(&error).unwrap();
+        assert_eq!(sender, SenderState::Done);
+
+        let mut receiver = ReceiverState::AwaitingFileName;
+        receiver.receive(&error).unwrap();
+        assert_eq!(receiver, ReceiverState::Done);
+    }
+}
@@ -0,0 +1,320 @@
+//! Checksums of data read from a file (or any other reader), for files too
+//! large to hold in memory.
+//!
+//! Only a chunk of the data is held at a time (plus the last
+//! `block_size - 1` bytes of the previous chunk for rolling checksums), and
+//! the checksums are exactly the ones the slice-based iterators of
+//! [`Checksums`] would yield for the whole data.
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read};
+
+use futures::{Stream, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::Checksums;
+
+/// The amount of data read at a time, by default.
+pub const CHUNK_SIZE: usize = 1 << 16;
+
+
+/// Checksums over a sliding window of a reader's data.
+#[derive(Debug, Clone)]
+pub struct Window<C> {
+    checksum: C,
+    /// Whether to compute the checksums at every byte offset, like
+    /// [`Checksums::checksums`], or of every block, like
+    /// [`Checksums::checksums_non_overlapping`].
+    rolling: bool,
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    /// Whether any checksums were computed yet.
+    started: bool,
+}
+
+impl<C: Checksums> Window<C> {
+    /// Checksums at every byte offset of the data.
+    pub fn rolling(checksum: C) -> Self {
+        Self::new(checksum, true)
+    }
+
+    /// Checksums of every block of the data.
+    pub fn non_overlapping(checksum: C) -> Self {
+        Self::new(checksum, false)
+    }
+
+    fn new(checksum: C, rolling: bool) -> Self {
+        Self {
+            checksum,
+            rolling,
+            chunk_size: CHUNK_SIZE,
+            buffer: vec![],
+            started: false,
+        }
+    }
+
+    /// Read (at least) `chunk_size` bytes at a time.
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// The checksums of the data read from `reader`.
+    pub fn read<R: Read>(self, reader: R) -> ReadChecksums<C, R> {
+        ReadChecksums {
+            window: self,
+            reader,
+            checksums: VecDeque::new(),
+            ended: false,
+        }
+    }
+
+    /// The checksums of the data read asynchronously from `reader`.
+    pub fn read_async<'a, R>(self, reader: R) -> impl Stream<Item = std::io::Result<C::Output>> + 'a
+    where
+        C: 'a,
+        C::Output: 'a,
+        R: AsyncRead + Unpin + 'a,
+    {
+        futures::stream::try_unfold((self, reader, false), |(mut window, mut reader, ended)| async move {
+            if ended {
+                return Ok::<_, std::io::Error>(None);
+            }
+            let end_of_data = window.fill_async(&mut reader).await?;
+            let checksums = window.next_checksums(end_of_data).into_iter().map(Ok);
+            Ok(Some((futures::stream::iter(checksums), (window, reader, end_of_data))))
+        })
+        .try_flatten()
+    }
+
+    /// The number of bytes to hold before computing checksums.
+    fn capacity(&self) -> usize {
+        let block_size = self.checksum.block_size();
+        match self.rolling {
+            true => block_size - 1 + self.chunk_size,
+            // Only ever hold whole blocks, so they aren't split across chunks.
+            false => self.chunk_size.div_ceil(block_size) * block_size,
+        }
+    }
+
+    /// Read until the window is full, returning whether the end of the data
+    /// was reached.
+    fn fill<R: Read>(&mut self, reader: &mut R) -> std::io::Result<bool> {
+        let capacity = self.capacity();
+        let mut filled = self.buffer.len();
+        self.buffer.resize(capacity, 0);
+        let result = loop {
+            if filled == capacity {
+                break Ok(false);
+            }
+            match reader.read(&mut self.buffer[filled..]) {
+                Ok(0) => break Ok(true),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => break Err(e),
+            }
+        };
+        self.buffer.truncate(filled);
+        result
+    }
+
+    async fn fill_async<R: AsyncRead + Unpin>(&mut self, reader: &mut R) -> std::io::Result<bool> {
+        let capacity = self.capacity();
+        let mut filled = self.buffer.len();
+        self.buffer.resize(capacity, 0);
+        let result = loop {
+            if filled == capacity {
+                break Ok(false);
+            }
+            match reader.read(&mut self.buffer[filled..]).await {
+                Ok(0) => break Ok(true),
+                Ok(read) => filled += read,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                Err(e) => break Err(e),
+            }
+        };
+        self.buffer.truncate(filled);
+        result
+    }
+
+    /// The checksums of everything in the window, keeping only what the
+    /// next checksums still need.
+    fn next_checksums(&mut self, end_of_data: bool) -> Vec<C::Output> {
+        if !self.rolling {
+            let checksums = self.checksum.checksums_non_overlapping(&self.buffer).collect();
+            self.buffer.clear();
+            return checksums;
+        }
+
+        let block_size = self.checksum.block_size();
+        if self.buffer.len() < block_size {
+            // Data shorter than a block has a single checksum, and otherwise
+            // these bytes were already covered by the previous windows.
+            if end_of_data && !self.started && !self.buffer.is_empty() {
+                self.started = true;
+                return self.checksum.checksums(&self.buffer).collect();
+            }
+            return vec![];
+        }
+        let checksums = self.checksum.checksums(&self.buffer).collect();
+        self.started = true;
+        // The next window starts right after the last one we computed.
+        self.buffer.drain(..self.buffer.len() + 1 - block_size);
+        checksums
+    }
+}
+
+
+/// An iterator over the checksums of the data read from a reader.
+#[derive(Debug)]
+pub struct ReadChecksums<C: Checksums, R> {
+    window: Window<C>,
+    reader: R,
+    checksums: VecDeque<C::Output>,
+    ended: bool,
+}
+
+impl<C: Checksums, R: Read> Iterator for ReadChecksums<C, R> {
+    type Item = std::io::Result<C::Output>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(checksum) = self.checksums.pop_front() {
+                return Some(Ok(checksum));
+            }
+            if self.ended {
+                return None;
+            }
+            match self.window.fill(&mut self.reader) {
+                Ok(end_of_data) => {
+                    self.checksums.extend(self.window.next_checksums(end_of_data));
+                    self.ended = end_of_data;
+                },
+                Err(e) => {
+                    self.ended = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+    }
+}
+
+
+/// Streaming variants of the [`Checksums`] iterators.
+pub trait StreamingChecksums: Checksums + Clone {
+    /// Like [`Checksums::checksums`], over the data read from `reader`.
+    fn read_checksums<R: Read>(&self, reader: R) -> ReadChecksums<Self, R> {
+        Window::rolling(self.clone()).read(reader)
+    }
+
+    /// Like [`Checksums::checksums_non_overlapping`], over the data read
+    /// from `reader`.
+    fn read_checksums_non_overlapping<R: Read>(&self, reader: R) -> ReadChecksums<Self, R> {
+        Window::non_overlapping(self.clone()).read(reader)
+    }
+
+    /// Like [`Checksums::checksums`], over the data read asynchronously from
+    /// `reader`.
+    fn read_checksums_async<'a, R>(&self, reader: R) -> impl Stream<Item = std::io::Result<Self::Output>> + 'a
+    where
+        Self: 'a,
+        Self::Output: 'a,
+        R: AsyncRead + Unpin + 'a,
+    {
+        Window::rolling(self.clone()).read_async(reader)
+    }
+
+    /// Like [`Checksums::checksums_non_overlapping`], over the data read
+    /// asynchronously from `reader`.
+    fn read_checksums_non_overlapping_async<'a, R>(&self, reader: R) -> impl Stream<Item = std::io::Result<Self::Output>> + 'a
+    where
+        Self: 'a,
+        Self::Output: 'a,
+        R: AsyncRead + Unpin + 'a,
+    {
+        Window::non_overlapping(self.clone()).read_async(reader)
+    }
+}
+
+impl<C: Checksums + Clone> StreamingChecksums for C {}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rolling_hash::RollingHashAlgorithm, strong_checksum::StrongCheckSum, weak_checksum::WeakCheckSum, CheckSum, ChecksumConfig};
+    use proptest::prelude::*;
+
+    /// A reader that never returns more than a few bytes at a time.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let length = buf.len().min(self.0.len()).min(3);
+            buf[..length].copy_from_slice(&self.0[..length]);
+            self.0 = &self.0[length..];
+            Ok(length)
+        }
+    }
+
+    fn check<C>(checksum: C, data: &[u8], chunk_size: usize) -> std::result::Result<(), TestCaseError>
+    where
+        C: Checksums + Clone,
+        C::Output: PartialEq + std::fmt::Debug,
+    {
+        for window in [Window::rolling(checksum.clone()), Window::non_overlapping(checksum.clone())] {
+            let expected: Vec<C::Output> = match window.rolling {
+                true => checksum.checksums(data).collect(),
+                false => checksum.checksums_non_overlapping(data).collect(),
+            };
+            let window = window.chunk_size(chunk_size);
+
+            let read = window.clone().read(data).collect::<std::io::Result<Vec<_>>>().unwrap();
+            prop_assert_eq!(&read, &expected);
+            let trickled = window.clone().read(Trickle(data)).collect::<std::io::Result<Vec<_>>>().unwrap();
+            prop_assert_eq!(&trickled, &expected);
+            let streamed = futures::executor::block_on(window.read_async(data).try_collect::<Vec<_>>()).unwrap();
+            prop_assert_eq!(&streamed, &expected);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_errors_are_reported() {
+        struct Broken;
+        impl Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::other("broken"))
+            }
+        }
+
+        let mut checksums = CheckSum::new().read_checksums(Broken);
+        assert!(checksums.next().unwrap().is_err());
+        assert!(checksums.next().is_none());
+    }
+
+    #[test]
+    fn data_spanning_several_chunks_is_checksummed() {
+        let data: Vec<u8> = (0..3 * CHUNK_SIZE + 17).map(|i| (i * 7 % 251) as u8).collect();
+        let checksum = WeakCheckSum::new();
+        let streamed = checksum.read_checksums(data.as_slice()).collect::<std::io::Result<Vec<_>>>().unwrap();
+        assert_eq!(streamed, checksum.checksums(&data).collect::<Vec<_>>());
+    }
+
+    proptest! {
+        #[test]
+        fn streamed_checksums_match_the_slice_iterators(
+            data in prop::collection::vec(0u8..=255, 0..=2000),
+            block_size in 1usize..=64,
+            chunk_size in 1usize..=200,
+        ) {
+            for &rolling_hash in RollingHashAlgorithm::ALL {
+                let config = ChecksumConfig { block_size, rolling_hash, ..Default::default() };
+                check(WeakCheckSum::with_config(&config), &data, chunk_size)?;
+            }
+            let config = ChecksumConfig { block_size, ..Default::default() };
+            check(StrongCheckSum::with_config(&config), &data, chunk_size)?;
+            check(CheckSum::with_config(&config), &data, chunk_size)?;
+        }
+    }
+}
@@ -1,13 +1,306 @@
+//! The strong checksums of blocks, and the whole-file digest.
+//!
+//! SHA-256 is always built in (authentication uses it anyway), and every
+//! other algorithm is behind a cargo feature of the same name (`md4`,
+//! `blake3` and `xxh3`). The peers agree on one of the algorithms both of
+//! them were built with (see `syncr::handshake`). Block
+//! checksums may be truncated to fewer bytes than the full 16, to send less
+//! over the wire (see [`derived_length`]), but the whole-file digest never is,
+//! so a block matched to the wrong data is still caught when the receiver
+//! verifies the file.
+
+use serde::{Serialize, Deserialize};
+
+/// The longest strong checksum, in bytes.
+pub const MAX_LENGTH: usize = 16;
+
+/// The shortest strong checksum picked by [`derived_length`], in bytes.
+pub const MIN_LENGTH: usize = 2;
+
+/// Derived lengths keep the odds of any block being matched to the wrong
+/// data below about one in `2^COLLISION_BITS` per file.
+pub const COLLISION_BITS: u32 = 20;
+
+
+/// The strong checksum length to use for a file of `file_size` bytes split
+/// into blocks of `block_size` bytes, given the number of bits in the weak
+/// checksum, like rsync's `s2length`.
+///
+/// The sender compares the weak and strong checksums of every block against
+/// the data at every offset of its file, so each extra byte of file or block
+/// makes a false match more likely. The receiver doesn't know the size of the
+/// sender's file, so its own is used instead: they're usually close.
+pub fn derived_length(file_size: usize, block_size: usize, weak_bits: u32) -> usize {
+    // Round the logarithms up, to err on the side of longer checksums.
+    let bits = |n: usize| usize::BITS - n.leading_zeros();
+    let comparisons = bits(file_size) + bits(file_size.div_ceil(block_size.max(1)));
+    let strong_bits = (COLLISION_BITS + comparisons).saturating_sub(weak_bits);
+    (strong_bits as usize).div_ceil(8).clamp(MIN_LENGTH, MAX_LENGTH)
+}
+
+
+/// A hash function strong enough to tell blocks with the same weak checksum
+/// apart.
+pub trait StrongHash {
+    /// The name of the algorithm in the handshake and configuration files.
+    fn name(&self) -> &'static str;
+
+    /// Hash `data` into `output`, which is at most [`MAX_LENGTH`] bytes long.
+    fn hash_into(&self, data: &[u8], output: &mut [u8]);
+
+    /// The hash of `data`, truncated to `length` bytes.
+    fn hash(&self, data: &[u8], length: usize) -> u128 {
+        let mut output = [0u8; MAX_LENGTH];
+        self.hash_into(data, &mut output[..length.min(MAX_LENGTH)]);
+        u128::from_le_bytes(output)
+    }
+
+    /// The full hash of a whole file.
+    fn digest(&self, data: &[u8]) -> u128 {
+        self.hash(data, MAX_LENGTH)
+    }
+
+    /// Start computing the [`StrongHash::digest`] of a file that is fed in
+    /// pieces, so it never has to be held in memory at once.
+    fn digester(&self) -> Box<dyn Digester>;
+}
+
+
+/// The whole-file digest of data fed in pieces (see [`StrongHash::digester`]).
+pub trait Digester: Send {
+    fn update(&mut self, data: &[u8]);
+
+    /// The digest of everything passed to [`Digester::update`].
+    fn finish(self: Box<Self>) -> u128;
+}
+
+/// The digest made of the first [`MAX_LENGTH`] bytes of a longer hash.
+fn truncated_digest(hash: &[u8]) -> u128 {
+    let mut output = [0u8; MAX_LENGTH];
+    output.copy_from_slice(&hash[..MAX_LENGTH]);
+    u128::from_le_bytes(output)
+}
+
+
+#[cfg(feature = "md4")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Md4;
+
+#[cfg(feature = "md4")]
+impl StrongHash for Md4 {
+    fn name(&self) -> &'static str {
+        "md4"
+    }
+
+    fn hash_into(&self, data: &[u8], output: &mut [u8]) {
+        use md4::Digest;
+        let digest = md4::Md4::digest(data);
+        output.copy_from_slice(&digest[..output.len()]);
+    }
+
+    fn digester(&self) -> Box<dyn Digester> {
+        Box::new(<md4::Md4 as md4::Digest>::new())
+    }
+}
+
+#[cfg(feature = "md4")]
+impl Digester for md4::Md4 {
+    fn update(&mut self, data: &[u8]) {
+        md4::Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> u128 {
+        truncated_digest(&md4::Digest::finalize(*self))
+    }
+}
+
+
+#[cfg(feature = "blake3")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Blake3;
+
+#[cfg(feature = "blake3")]
+impl StrongHash for Blake3 {
+    fn name(&self) -> &'static str {
+        "blake3"
+    }
+
+    fn hash_into(&self, data: &[u8], output: &mut [u8]) {
+        let digest = blake3::hash(data);
+        output.copy_from_slice(&digest.as_bytes()[..output.len()]);
+    }
+
+    fn digester(&self) -> Box<dyn Digester> {
+        Box::new(blake3::Hasher::new())
+    }
+}
+
+#[cfg(feature = "blake3")]
+impl Digester for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> u128 {
+        truncated_digest(self.finalize().as_bytes())
+    }
+}
+
+
+/// The 128-bit variant of XXH3. It isn't a cryptographic hash, but it's much
+/// faster than the others.
+#[cfg(feature = "xxh3")]
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Xxh3;
+
+#[cfg(feature = "xxh3")]
+impl StrongHash for Xxh3 {
+    fn name(&self) -> &'static str {
+        "xxh3-128"
+    }
+
+    fn hash_into(&self, data: &[u8], output: &mut [u8]) {
+        let digest = xxhash_rust::xxh3::xxh3_128(data).to_le_bytes();
+        output.copy_from_slice(&digest[..output.len()]);
+    }
+
+    fn digester(&self) -> Box<dyn Digester> {
+        Box::new(xxhash_rust::xxh3::Xxh3Default::new())
+    }
+}
+
+#[cfg(feature = "xxh3")]
+impl Digester for xxhash_rust::xxh3::Xxh3Default {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3Default::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> u128 {
+        self.digest128()
+    }
+}
+
+
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Sha256;
+
+impl StrongHash for Sha256 {
+    fn name(&self) -> &'static str {
+        "sha256"
+    }
+
+    fn hash_into(&self, data: &[u8], output: &mut [u8]) {
+        use sha2::Digest;
+        let digest = sha2::Sha256::digest(data);
+        output.copy_from_slice(&digest[..output.len()]);
+    }
+
+    fn digester(&self) -> Box<dyn Digester> {
+        Box::new(<sha2::Sha256 as sha2::Digest>::new())
+    }
+}
+
+impl Digester for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        sha2::Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> u128 {
+        truncated_digest(&sha2::Digest::finalize(*self))
+    }
+}
+
+
+/// One of the strong hashes this build supports.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, clap::ValueEnum)]
+pub enum StrongHashAlgorithm {
+    #[cfg(feature = "blake3")]
+    #[serde(rename = "blake3")]
+    #[value(name = "blake3")]
+    Blake3,
+    #[serde(rename = "sha256")]
+    #[value(name = "sha256")]
+    Sha256,
+    #[cfg(feature = "xxh3")]
+    #[serde(rename = "xxh3-128")]
+    #[value(name = "xxh3-128")]
+    Xxh3,
+    #[cfg(feature = "md4")]
+    #[serde(rename = "md4")]
+    #[value(name = "md4")]
+    Md4,
+}
+
+impl StrongHashAlgorithm {
+    /// Every strong hash this build supports, in order of preference.
+    pub const ALL: &'static [Self] = &[
+        #[cfg(feature = "blake3")]
+        Self::Blake3,
+        Self::Sha256,
+        #[cfg(feature = "xxh3")]
+        Self::Xxh3,
+        #[cfg(feature = "md4")]
+        Self::Md4,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|algorithm| algorithm.name() == name)
+    }
+
+    fn implementation(&self) -> &'static dyn StrongHash {
+        match self {
+            #[cfg(feature = "blake3")]
+            Self::Blake3 => &Blake3,
+            Self::Sha256 => &Sha256,
+            #[cfg(feature = "xxh3")]
+            Self::Xxh3 => &Xxh3,
+            #[cfg(feature = "md4")]
+            Self::Md4 => &Md4,
+        }
+    }
+}
+
+impl Default for StrongHashAlgorithm {
+    fn default() -> Self {
+        Self::ALL[0]
+    }
+}
+
+impl StrongHash for StrongHashAlgorithm {
+    fn name(&self) -> &'static str {
+        self.implementation().name()
+    }
+
+    fn hash_into(&self, data: &[u8], output: &mut [u8]) {
+        self.implementation().hash_into(data, output)
+    }
+
+    fn digester(&self) -> Box<dyn Digester> {
+        self.implementation().digester()
+    }
+}
+
+impl std::fmt::Display for StrongHashAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 
 #[derive(Debug, Copy, Clone)]
 pub struct StrongCheckSum {
     pub block_size: usize,
+    pub algorithm: StrongHashAlgorithm,
+    /// The length of each block's checksum, in bytes.
+    pub length: usize,
 }
 
 impl Default for StrongCheckSum {
     fn default() -> Self {
         Self {
             block_size: 1000,
+            algorithm: StrongHashAlgorithm::default(),
+            length: MAX_LENGTH,
         }
     }
 }
@@ -20,11 +313,23 @@ impl StrongCheckSum {
     pub fn with_config(config: &ChecksumConfig) -> Self {
         Self {
             block_size: config.block_size,
+            algorithm: config.strong_hash,
+            length: config.strong_length.unwrap_or(MAX_LENGTH),
         }
     }
-    
-    pub fn checksum_for_block<'buf>(&self, starting_index: usize, data: &'buf [u8] ) -> u128 {
-        hash(&data[starting_index..starting_index + self.block_size])
+
+    /// The (possibly truncated) checksum of a single block.
+    pub fn hash(&self, block: &[u8]) -> u128 {
+        self.algorithm.hash(block, self.length)
+    }
+
+    /// The full digest of a whole file.
+    pub fn digest(&self, data: &[u8]) -> u128 {
+        self.algorithm.digest(data)
+    }
+
+    pub fn checksum_for_block(&self, starting_index: usize, data: &[u8] ) -> u128 {
+        self.hash(&data[starting_index..starting_index + self.block_size])
     }
 }
 
@@ -34,6 +339,7 @@ impl Checksums for StrongCheckSum {
         if data.len() < self.block_size {
             return Box::new(
                 StrongCheckSumIterator {
+                    checksum: *self,
                     data,
                     left_index: 0,
                     right_index: data.len(),
@@ -43,6 +349,7 @@ impl Checksums for StrongCheckSum {
         }
         Box::new(
             StrongCheckSumIterator {
+                checksum: *self,
                 data,
                 left_index: 0,
                 right_index: self.block_size,
@@ -52,20 +359,19 @@ impl Checksums for StrongCheckSum {
     }
 
     fn checksums_non_overlapping<'buf>(&self, data: &'buf [u8]) -> Box<dyn Iterator<Item=Self::Output> + 'buf> {
-        Box::new(
-            StrongCheckSumIterator {
-                data,
-                left_index: 0,
-                right_index: data.len().min(self.block_size),
-                shift: data.len().min(self.block_size)
-            }
-        )
+        let checksum = *self;
+        Box::new(data.chunks(self.block_size).map(move |block| checksum.hash(block)))
+    }
+
+    fn block_size(&self) -> usize {
+        self.block_size
     }
 }
 
 
 #[derive(Debug)]
 pub struct StrongCheckSumIterator<'buf> {
+    checksum: StrongCheckSum,
     data: &'buf [u8],
     left_index: usize,
     right_index: usize,
@@ -76,32 +382,16 @@ impl<'buf> Iterator for StrongCheckSumIterator<'buf> {
     type Item = u128;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.right_index >= self.data.len() {
+        if self.right_index > self.data.len() || self.left_index >= self.data.len() {
             return None;
         }
-        let result = hash(&self.data[self.left_index..self.right_index]);
+        let result = self.checksum.hash(&self.data[self.left_index..self.right_index]);
         self.left_index += self.shift;
         self.right_index += self.shift;
         Some(result)
     }
 }
 
-
-#[cfg(feature = "md4")]
-mod _md4 {
-    use md4::{Md4, Digest};
-
-    pub fn hash(data: &[u8]) -> u128 {
-        let mut hasher = Md4::new();
-        hasher.update(data);
-        let result: [u8; 16] = hasher.finalize().into();
-        u128::from_le_bytes(result)
-    }
-}
-
-#[cfg(feature = "md4")]
-pub use _md4::*;
-
 use crate::{ChecksumConfig, Checksums};
 
 
@@ -118,4 +408,75 @@ mod tests {
         // assert_eq!(checksums[0], 0x6d9f9b5a_0d5f9b5a_0d5f9b5a_0d5f9b5a);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn non_overlapping_checksums_include_the_last_block() {
+        let data = [7u8; 3000];
+        let checksum = StrongCheckSum::new();
+        assert_eq!(checksum.checksums_non_overlapping(&data).count(), 3);
+        assert_eq!(checksum.checksums(&data).count(), 2001);
+
+        let checksums: Vec<u128> = checksum.checksums_non_overlapping(&data[..2500]).collect();
+        assert_eq!(checksums, vec![checksum.hash(&data[..1000]), checksum.hash(&data[..1000]), checksum.hash(&data[..500])]);
+    }
+
+    #[test]
+    fn every_algorithm_has_a_distinct_name() {
+        for algorithm in StrongHashAlgorithm::ALL {
+            assert_eq!(StrongHashAlgorithm::from_name(algorithm.name()), Some(*algorithm));
+        }
+        assert_eq!(StrongHashAlgorithm::from_name("crc32"), None);
+    }
+
+    #[test]
+    fn checksums_are_truncated_to_their_length() {
+        for &algorithm in StrongHashAlgorithm::ALL {
+            let full = algorithm.digest(b"some block");
+            assert_ne!(full, algorithm.digest(b"some other block"));
+            for length in 1..=MAX_LENGTH {
+                let mask = u128::MAX >> (8 * (MAX_LENGTH - length));
+                assert_eq!(algorithm.hash(b"some block", length), full & mask, "{} truncated to {}", algorithm, length);
+            }
+        }
+    }
+
+    #[test]
+    fn digesters_match_the_digest() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+        for &algorithm in StrongHashAlgorithm::ALL {
+            let mut digester = algorithm.digester();
+            for piece in data.chunks(777) {
+                digester.update(piece);
+            }
+            assert_eq!(digester.finish(), algorithm.digest(&data), "{}", algorithm);
+            assert_eq!(algorithm.digester().finish(), algorithm.digest(b""), "{}", algorithm);
+        }
+    }
+
+    #[test]
+    fn derived_lengths_grow_with_the_file() {
+        // Small files get the shortest checksums.
+        assert_eq!(derived_length(0, 1000, 32), MIN_LENGTH);
+        assert_eq!(derived_length(1000, 1000, 32), MIN_LENGTH);
+        // 20 + 21 + 11 - 32 bits for a mebibyte.
+        assert_eq!(derived_length(1 << 20, 1000, 32), 3);
+        // 20 + 31 + 21 - 32 bits for a gibibyte.
+        assert_eq!(derived_length(1 << 30, 1000, 32), 5);
+        // A weaker weak checksum needs a stronger strong one.
+        assert_eq!(derived_length(1 << 30, 1000, 16), 7);
+        assert_eq!(derived_length(usize::MAX, 1, 0), MAX_LENGTH);
+
+        let mut previous = 0;
+        for size in (0..48).map(|shift| 1usize << shift) {
+            let length = derived_length(size, 700, 32);
+            assert!(length >= previous);
+            previous = length;
+        }
+    }
+
+    #[cfg(feature = "md4")]
+    #[test]
+    fn md4_matches_the_reference() {
+        // RFC 1320's test suite.
+        assert_eq!(Md4.digest(b"abc").to_le_bytes(), *b"\xa4\x48\x01\x7a\xaf\x21\xd8\x52\x5f\xc1\x0a\xe8\x7a\xa6\x72\x9d");
+    }
+}
@@ -0,0 +1,128 @@
+//! systemd socket activation.
+//!
+//! When started by a systemd `.socket` unit, `syncrd` is handed the already
+//! bound listening socket as file descriptor 3, and told so through the
+//! `LISTEN_PID` and `LISTEN_FDS` environment variables (see
+//! `sd_listen_fds(3)`). This lets it listen on privileged ports, or on sockets
+//! in directories it can't write to, without running as root.
+
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+
+use crate::SyncrError;
+
+/// The first file descriptor passed by systemd.
+pub const LISTEN_FDS_START: RawFd = 3;
+
+
+/// A listening socket passed by systemd.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+impl Listener {
+    /// Take over a listening socket of either kind, ready to be used with
+    /// tokio.
+    pub fn from_fd(fd: OwnedFd) -> std::io::Result<Self> {
+        // A Unix socket has no IP address, and vice versa.
+        let tcp = std::net::TcpListener::from(fd);
+        let listener = match tcp.local_addr() {
+            Ok(_) => Listener::Tcp(tcp),
+            Err(_) => {
+                let unix = std::os::unix::net::UnixListener::from(OwnedFd::from(tcp));
+                unix.local_addr()?;
+                Listener::Unix(unix)
+            },
+        };
+        match &listener {
+            Listener::Tcp(tcp) => tcp.set_nonblocking(true)?,
+            Listener::Unix(unix) => unix.set_nonblocking(true)?,
+        }
+        Ok(listener)
+    }
+}
+
+
+/// The number of sockets systemd passed to the process `pid`, given the
+/// values of `LISTEN_PID` and `LISTEN_FDS`.
+pub fn listen_fds(listen_pid: Option<&str>, listen_fds: Option<&str>, pid: u32) -> usize {
+    // The variables may have been inherited from our parent, so only trust
+    // them if they're meant for us.
+    if listen_pid.and_then(|listen_pid| listen_pid.parse::<u32>().ok()) != Some(pid) {
+        return 0;
+    }
+    listen_fds.and_then(|count| count.parse().ok()).unwrap_or(0)
+}
+
+
+/// The number of sockets systemd passed to us, if we were socket-activated.
+///
+/// The variables are removed from the environment, so they aren't passed on
+/// to our children. Changing the environment while another thread may be
+/// reading it is unsound, so this must be called before starting any thread
+/// (such as tokio's runtime), and only once.
+pub fn take_listen_fds() -> usize {
+    let count = listen_fds(
+        std::env::var("LISTEN_PID").ok().as_deref(),
+        std::env::var("LISTEN_FDS").ok().as_deref(),
+        std::process::id(),
+    );
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    count
+}
+
+
+/// The listening socket passed by systemd, given the number of sockets
+/// returned by [`take_listen_fds`].
+///
+/// The socket may only be taken over once, so this must be called at most
+/// once.
+pub fn listener(count: usize) -> crate::Result<Option<Listener>> {
+    match count {
+        0 => Ok(None),
+        1 => {
+            // Safety: systemd passed us this file descriptor for us to own,
+            // and nothing else takes it over.
+            let fd = unsafe { OwnedFd::from_raw_fd(LISTEN_FDS_START) };
+            Ok(Some(Listener::from_fd(fd)?))
+        },
+        count => Err(SyncrError::InvalidConfig {
+            path: "LISTEN_FDS".to_string(),
+            reason: format!("expected a single socket, but systemd passed {}", count),
+        }),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_sockets_meant_for_us_are_used() {
+        assert_eq!(listen_fds(Some("42"), Some("1"), 42), 1);
+        assert_eq!(listen_fds(Some("41"), Some("1"), 42), 0);
+        assert_eq!(listen_fds(None, Some("1"), 42), 0);
+        assert_eq!(listen_fds(Some("42"), None, 42), 0);
+        assert_eq!(listen_fds(Some("42"), Some("many"), 42), 0);
+    }
+
+    #[test]
+    fn sockets_of_either_kind_are_recognized() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = tcp.local_addr().unwrap();
+        match Listener::from_fd(OwnedFd::from(tcp)).unwrap() {
+            Listener::Tcp(listener) => assert_eq!(listener.local_addr().unwrap(), address),
+            listener => panic!("expected a TCP listener, got {:?}", listener),
+        }
+
+        let path = std::env::temp_dir().join(format!("syncr-systemd-test-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(matches!(Listener::from_fd(OwnedFd::from(unix)).unwrap(), Listener::Unix(_)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
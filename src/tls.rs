@@ -0,0 +1,177 @@
+//! TLS for connections between `syncr` and `syncrd`.
+//!
+//! Everything here only sets up the `rustls` configuration from PEM files;
+//! the TLS streams are then handed to [`Connection::from_stream`] like any
+//! other transport.
+//!
+//! [`Connection::from_stream`]: crate::network::Connection::from_stream
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use tokio_rustls::rustls::{
+    self,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::WebPkiClientVerifier,
+    ClientConfig,
+    RootCertStore,
+};
+pub use tokio_rustls::{rustls::ServerConfig, TlsAcceptor, TlsConnector};
+
+use crate::SyncrError;
+
+
+/// Accept TLS connections with the certificate chain and private key in the
+/// given PEM files.
+///
+/// If `client_ca` is given, clients must present a certificate signed by one
+/// of the CAs in it (mutual TLS).
+pub fn acceptor(cert: &Path, key: &Path, client_ca: Option<&Path>) -> crate::Result<TlsAcceptor> {
+    Ok(TlsAcceptor::from(server_config(cert, key, client_ca)?))
+}
+
+/// The configuration behind [`acceptor`], which can be checked (and kept
+/// around) before accepting any connection.
+pub fn server_config(cert: &Path, key: &Path, client_ca: Option<&Path>) -> crate::Result<Arc<ServerConfig>> {
+    let builder = match client_ca {
+        Some(client_ca) => {
+            let verifier = WebPkiClientVerifier::builder(Arc::new(load_roots(client_ca)?))
+                .build()
+                .map_err(|e| invalid(client_ca, e))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        },
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+    Ok(Arc::new(config))
+}
+
+
+/// Connect to servers whose certificate is signed by one of the CAs in the
+/// `ca` PEM bundle, optionally presenting a client certificate and key.
+pub fn connector(ca: &Path, identity: Option<(&Path, &Path)>) -> crate::Result<TlsConnector> {
+    let builder = ClientConfig::builder().with_root_certificates(load_roots(ca)?);
+    let config = match identity {
+        Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+        None => builder.with_no_client_auth(),
+    };
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+
+/// The name the server's certificate is checked against.
+pub fn server_name(name: &str) -> crate::Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string())
+        .map_err(|e| rustls::Error::General(format!("Invalid server name {:?}: {}", name, e)).into())
+}
+
+
+fn load_certs(path: &Path) -> crate::Result<Vec<CertificateDer<'static>>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> crate::Result<PrivateKeyDer<'static>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?.ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn load_roots(path: &Path) -> crate::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(path)? {
+        roots.add(cert)?;
+    }
+    Ok(roots)
+}
+
+fn invalid(path: &Path, reason: impl std::fmt::Display) -> SyncrError {
+    rustls::Error::General(format!("{}: {}", path.display(), reason)).into()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::{Connection, Limits, Message};
+    use rcgen::{BasicConstraints, CertificateParams, CertifiedIssuer, IsCa, KeyPair};
+    use std::path::PathBuf;
+
+    /// A CA, and a server and a client certificate signed by it, as PEM files
+    /// in a fresh directory.
+    fn write_pki(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!("syncr-tls-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut ca_params = CertificateParams::new(Vec::<String>::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        let ca = CertifiedIssuer::self_signed(ca_params, KeyPair::generate().unwrap()).unwrap();
+        std::fs::write(directory.join("ca.pem"), ca.pem()).unwrap();
+
+        for (entity, names) in [("server", vec!["localhost".to_string()]), ("client", vec![])] {
+            let key = KeyPair::generate().unwrap();
+            let cert = CertificateParams::new(names).unwrap().signed_by(&key, &ca).unwrap();
+            std::fs::write(directory.join(format!("{}.pem", entity)), cert.pem()).unwrap();
+            std::fs::write(directory.join(format!("{}.key", entity)), key.serialize_pem()).unwrap();
+        }
+        directory
+    }
+
+    #[tokio::test]
+    async fn connections_talk_over_mutual_tls() {
+        let pki = write_pki("mutual");
+        let acceptor = acceptor(&pki.join("server.pem"), &pki.join("server.key"), Some(&pki.join("ca.pem"))).unwrap();
+        let connector = connector(&pki.join("ca.pem"), Some((&pki.join("client.pem"), &pki.join("client.key")))).unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (client, server) = tokio::join!(
+            connector.connect(server_name("localhost").unwrap(), client),
+            acceptor.accept(server),
+        );
+
+        let (client_inbound_tx, _client_inbound_rx) = tokio::sync::mpsc::channel(10);
+        let (client_outbound_tx, client_outbound_rx) = tokio::sync::mpsc::channel(10);
+        let client = Connection::from_stream(client.unwrap(), client_inbound_tx, Limits::default());
+
+        let (server_inbound_tx, mut server_inbound_rx) = tokio::sync::mpsc::channel(10);
+        let (_server_outbound_tx, server_outbound_rx) = tokio::sync::mpsc::channel(10);
+        let server = Connection::from_stream(server.unwrap(), server_inbound_tx, Limits::default());
+
+        tokio::spawn(client.run(client_outbound_rx));
+        tokio::spawn(server.run(server_outbound_rx));
+
+        client_outbound_tx.send(Message::FileName("a.txt".to_string())).await.unwrap();
+        assert!(matches!(server_inbound_rx.recv().await, Some(Message::FileName(name)) if name == "a.txt"));
+        std::fs::remove_dir_all(&pki).unwrap();
+    }
+
+    #[tokio::test]
+    async fn clients_without_a_certificate_are_rejected() {
+        let pki = write_pki("anonymous");
+        let acceptor = acceptor(&pki.join("server.pem"), &pki.join("server.key"), Some(&pki.join("ca.pem"))).unwrap();
+        let connector = connector(&pki.join("ca.pem"), None).unwrap();
+
+        let (client, server) = tokio::io::duplex(1024);
+        let (_, server) = tokio::join!(
+            connector.connect(server_name("localhost").unwrap(), client),
+            acceptor.accept(server),
+        );
+        assert!(server.is_err());
+        std::fs::remove_dir_all(&pki).unwrap();
+    }
+
+    #[test]
+    fn missing_keys_are_reported() {
+        let pki = write_pki("missing-key");
+        assert!(matches!(
+            acceptor(&pki.join("server.pem"), &pki.join("server.pem"), None),
+            Err(SyncrError::Tls(_))
+        ));
+        std::fs::remove_dir_all(&pki).unwrap();
+    }
+}
@@ -1,4 +1,5 @@
 use crate::{ChecksumConfig, Checksums};
+use crate::rolling_hash::{Adler32, Buzhash, RabinKarp, RollingChecksums, RollingHash, RollingHashAlgorithm, Rsync};
 
 
 
@@ -11,6 +12,9 @@ use crate::{ChecksumConfig, Checksums};
 /// but it is a good heuristic for a first-pass. Only those code blocks
 /// that pass the weak checksum are then compared using the
 /// strong checksum.
+///
+/// The rsync checksum is used by default, but any of the other
+/// [rolling hashes](crate::rolling_hash) may be picked instead.
 /// 
 /// [Rsync Algorithm]: https://www.andrew.cmu.edu/course/15-749/READINGS/required/cas/tridgell96.pdf
 #[derive(Debug, Copy, Clone)]
@@ -20,12 +24,15 @@ pub struct WeakCheckSum {
     modulus: u32,
     /// The size of each chunk to calculate a running checksum for (i.e. window size).
     block_size: usize,
+    /// The rolling hash to use.
+    algorithm: RollingHashAlgorithm,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct WeakCheckSumBuilder {
     modulus: Option<u32>,
     block_size: Option<usize>,
+    algorithm: Option<RollingHashAlgorithm>,
 }
 
 impl WeakCheckSumBuilder {
@@ -33,6 +40,7 @@ impl WeakCheckSumBuilder {
         Self {
             modulus: None,
             block_size: None,
+            algorithm: None,
         }
     }
 
@@ -47,10 +55,16 @@ impl WeakCheckSumBuilder {
         self
     }
 
+    pub fn algorithm(mut self, algorithm: RollingHashAlgorithm) -> Self {
+        self.algorithm = Some(algorithm);
+        self
+    }
+
     pub fn build(self) -> WeakCheckSum {
         WeakCheckSum {
             modulus: self.modulus.unwrap_or(1 << 16),
             block_size: self.block_size.unwrap_or(1000),
+            algorithm: self.algorithm.unwrap_or_default(),
         }
     }
 }
@@ -60,6 +74,7 @@ impl Default for WeakCheckSum {
         Self {
             modulus: 1 << 16,
             block_size: 1000,
+            algorithm: RollingHashAlgorithm::default(),
         }
     }
 }
@@ -71,7 +86,22 @@ impl WeakCheckSum {
     pub fn with_config(config: &ChecksumConfig) -> Self {
         Self {
             block_size: config.block_size,
-            modulus: config.modulus
+            modulus: config.modulus,
+            algorithm: config.rolling_hash,
+        }
+    }
+
+    pub fn algorithm(&self) -> RollingHashAlgorithm {
+        self.algorithm
+    }
+
+    /// The number of bits the checksums are spread over.
+    pub fn bits(&self) -> u32 {
+        match self.algorithm {
+            RollingHashAlgorithm::Rsync => Rsync { modulus: self.modulus }.bits(),
+            RollingHashAlgorithm::Adler32 => Adler32.bits(),
+            RollingHashAlgorithm::Buzhash => Buzhash.bits(),
+            RollingHashAlgorithm::RabinKarp => RabinKarp.bits(),
         }
     }
 
@@ -80,8 +110,8 @@ impl WeakCheckSum {
             return 0;
         }
         let mut sum: u32 = 0;
-        for i in left..=right {
-            let summand = buffer[i] as u32;
+        for &byte in &buffer[left..=right] {
+            let summand = byte as u32;
             sum = (sum + summand) % modulus
         }
         sum % modulus
@@ -92,8 +122,8 @@ impl WeakCheckSum {
             return 0;
         }
         let mut sum = 0;
-        for i in left..=right {
-            let summand = (buffer[i] as u32) * (right - i + 1) as u32;
+        for (i, &byte) in buffer.iter().enumerate().take(right + 1).skip(left) {
+            let summand = (byte as u32) * (right - i + 1) as u32;
             sum = (sum + summand) % modulus;
         }
         sum % modulus
@@ -106,48 +136,18 @@ impl Checksums for WeakCheckSum {
 
     fn checksums<'buf>(&self, buffer: &'buf [u8]) -> Box<dyn Iterator<Item=Self::Output> + 'buf > {
         let block_size = self.block_size;
-        if buffer.len() == 0 {
-            return Box::new(
-                WeakCheckSumRollingIterator {
-                    buffer,
-                    modulus: self.modulus as isize,
-                    previous_k: 0,
-                    previous_l: 0,
-                    a_k_l: 0,
-                    b_k_l: 0,
-                    ended: true,
-                }
-            );
-        }
-        if buffer.len() < block_size {
-            return Box::new(
-                WeakCheckSumRollingIterator {
-                    buffer,
-                    modulus: self.modulus as isize,
-                    previous_k: 0,
-                    previous_l: buffer.len() - 1,
-                    a_k_l: WeakCheckSum::a_expanded(self.modulus, 0, buffer.len() - 1, buffer) as isize,
-                    b_k_l: WeakCheckSum::b_expanded(self.modulus, 0, buffer.len() - 1, buffer) as isize,
-                    ended: false,
-                }
-            );
+        match self.algorithm {
+            RollingHashAlgorithm::Rsync => Box::new(RollingChecksums::new(Rsync { modulus: self.modulus }, buffer, block_size)),
+            RollingHashAlgorithm::Adler32 => Box::new(RollingChecksums::new(Adler32, buffer, block_size)),
+            RollingHashAlgorithm::Buzhash => Box::new(RollingChecksums::new(Buzhash, buffer, block_size)),
+            RollingHashAlgorithm::RabinKarp => Box::new(RollingChecksums::new(RabinKarp, buffer, block_size)),
         }
-        Box::new(
-            WeakCheckSumRollingIterator {
-                buffer,
-                modulus: self.modulus as isize,
-                previous_k: 0,
-                previous_l: block_size - 1,
-                a_k_l: WeakCheckSum::a_expanded(self.modulus, 0, block_size - 1, buffer) as isize,
-                b_k_l: WeakCheckSum::b_expanded(self.modulus, 0, block_size - 1, buffer) as isize,
-                ended: false,
-            }
-        )
     }
 
     fn checksums_non_overlapping<'buf>(&self, data: &'buf [u8]) -> Box<dyn Iterator<Item=Self::Output> + 'buf> {
         let iterator = self.checksums(data).step_by(self.block_size);
-        if data.len() % self.block_size != 0 {
+        // Data shorter than a block is a single (short) block already.
+        if data.len() > self.block_size && data.len() % self.block_size != 0 {
             let last_chunk_start_index = data.len() - (data.len() % self.block_size);
             let last_chunk = data[last_chunk_start_index..].as_ref();
             return Box::new(
@@ -159,17 +159,10 @@ impl Checksums for WeakCheckSum {
             iterator
         )
     }
-}
 
-#[derive(Debug)]
-pub struct WeakCheckSumRollingIterator<'buf> {
-    buffer: &'buf [u8],
-    modulus: isize,
-    previous_k: usize,
-    previous_l: usize,
-    a_k_l: isize,
-    b_k_l: isize,
-    ended: bool,
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
 }
 
 #[derive(Debug)]
@@ -189,8 +182,8 @@ impl<'buf> Iterator for WeakChecksumNonOverlappingIterator<'buf> {
             return None;
         }
         let checksum = 
-            WeakCheckSum::a_expanded(self.modulus as u32, self.left, self.right, self.buffer) as u32    
-            + (WeakCheckSum::b_expanded(self.modulus as u32, self.left, self.right, self.buffer) as u32) << 16;
+            WeakCheckSum::a_expanded(self.modulus as u32, self.left, self.right, self.buffer)
+            + (WeakCheckSum::b_expanded(self.modulus as u32, self.left, self.right, self.buffer) << 16);
         
         self.left += self.window_size;
         self.right += self.window_size;
@@ -199,45 +192,6 @@ impl<'buf> Iterator for WeakChecksumNonOverlappingIterator<'buf> {
 }
 
 
-impl<'buf> Iterator for WeakCheckSumRollingIterator<'buf> {
-    type Item = u32;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.ended {
-            return None;
-        }
-        if self.previous_l + 1 >= self.buffer.len() || self.previous_k >= self.buffer.len() {
-            let checksum = self.a_k_l + (self.b_k_l << 16);
-            self.ended = true;
-            return Some(checksum as u32);
-        }
-        let new_a_k_l = (
-            self.a_k_l 
-            + (self.buffer[self.previous_l + 1] as isize)
-            - (self.buffer[self.previous_k] as isize)
-        ).rem_euclid(self.modulus);
-
-        let new_b_k_l = (
-            self.b_k_l 
-            + new_a_k_l 
-            - (
-                (self.previous_l as isize - self.previous_k as isize + 1) * (self.buffer[self.previous_k] as isize)
-            )
-        ).rem_euclid(self.modulus);
-
-        self.previous_k += 1;
-        self.previous_l += 1;
-
-        let checksum = self.a_k_l + (self.b_k_l << 16);
-
-        self.a_k_l = new_a_k_l.rem_euclid(self.modulus);
-        self.b_k_l = new_b_k_l.rem_euclid(self.modulus);
-
-        Some(checksum as u32)
-    }
-}
-
-
 /// Utility function to compute the rolling weak checksum of a buffer
 /// meant for a direct public API.
 pub fn rolling_checksum(buffer: &'static [u8]) -> Vec<u32> {
@@ -252,6 +206,21 @@ mod tests {
     use super::*;
     use proptest::prelude::*;
 
+    #[test]
+    fn non_overlapping_checksums_pack_b_above_a() {
+        let buffer = [1, 2, 3, 4];
+        let mut checksums = WeakChecksumNonOverlappingIterator {
+            buffer: &buffer,
+            modulus: 1 << 16,
+            left: 0,
+            right: 1,
+            window_size: 2,
+        };
+        assert_eq!(checksums.next(), Some(3 + (4 << 16)));
+        assert_eq!(checksums.next(), Some(7 + (10 << 16)));
+        assert_eq!(checksums.next(), None);
+    }
+
     #[test]
     fn rolling_checksum_of_buffer() {
         let buffer = [0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09];
@@ -267,6 +236,24 @@ mod tests {
         assert_eq!(rolling_checksum.checksums(&buffer).count(), 0);
     }
 
+    #[test]
+    fn non_overlapping_checksums_have_one_entry_per_block() {
+        let rolling_checksum = WeakCheckSum::new();
+        assert_eq!(rolling_checksum.checksums_non_overlapping(&[7u8; 500]).count(), 1);
+        assert_eq!(rolling_checksum.checksums_non_overlapping(&[7u8; 2000]).count(), 2);
+        assert_eq!(rolling_checksum.checksums_non_overlapping(&[7u8; 2500]).count(), 3);
+    }
+
+    #[test]
+    fn non_overlapping_checksums_hash_each_block() {
+        let data: Vec<u8> = (0..2500u32).map(|i| (i * 31 % 251) as u8).collect();
+        for &algorithm in RollingHashAlgorithm::ALL {
+            let checksum = WeakCheckSumBuilder::new().algorithm(algorithm).build();
+            let expected: Vec<u32> = data.chunks(1000).map(|block| checksum.checksums(block).next().unwrap()).collect();
+            assert_eq!(checksum.checksums_non_overlapping(&data).collect::<Vec<_>>(), expected, "{}", algorithm);
+        }
+    }
+
     proptest! {
 
         #[test]
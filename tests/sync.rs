@@ -0,0 +1,118 @@
+//! Whole sessions between the `syncr` and `syncrd` binaries, talking over a
+//! remote shell.
+
+#![cfg(unix)]
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+
+fn directory(name: &str) -> PathBuf {
+    let directory = std::env::temp_dir().join(format!("syncr-sync-{}-{}", name, std::process::id()));
+    std::fs::create_dir_all(directory.join("root")).unwrap();
+    directory
+}
+
+/// Sync `file` to `remote_file` in a `syncrd` serving `root`, started with
+/// the extra `options`.
+fn sync(file: &Path, root: &Path, remote_file: &str, options: &str) -> std::process::ExitStatus {
+    Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .arg("--file")
+        .arg(file)
+        .arg("--remote-file")
+        .arg(remote_file)
+        .arg("--rsh")
+        .arg(format!("'{}' --server --root '{}' {}", env!("CARGO_BIN_EXE_syncrd"), root.display(), options))
+        .status()
+        .unwrap()
+}
+
+#[test]
+fn new_files_are_created() {
+    let directory = directory("new");
+    let file = directory.join("file.txt");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    assert!(sync(&file, &directory.join("root"), "new.txt", "").success());
+    assert_eq!(std::fs::read(directory.join("root/new.txt")).unwrap(), data);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn existing_files_are_updated() {
+    let directory = directory("existing");
+    let file = directory.join("file.txt");
+    let data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+    std::fs::write(directory.join("root/old.txt"), &data[2_000..]).unwrap();
+
+    assert!(sync(&file, &directory.join("root"), "old.txt", "").success());
+    assert_eq!(std::fs::read(directory.join("root/old.txt")).unwrap(), data);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn files_larger_than_a_frame_are_streamed() {
+    let directory = directory("large");
+    let file = directory.join("file.bin");
+    // Bytes past 127 take two bytes each on the wire.
+    let data: Vec<u8> = (0..6 * 1024 * 1024u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect();
+    std::fs::write(&file, &data).unwrap();
+
+    let options = format!("--max-frame-size {}", syncr::network::MIN_FRAME_SIZE);
+    assert!(sync(&file, &directory.join("root"), "large.bin", &options).success());
+    assert_eq!(std::fs::read(directory.join("root/large.bin")).unwrap(), data);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn failing_remote_shells_are_reported() {
+    let directory = directory("rsh");
+    let file = directory.join("file.txt");
+    std::fs::write(&file, b"data").unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_syncr"))
+        .arg("--file")
+        .arg(&file)
+        .arg("--remote-file")
+        .arg("file.txt")
+        .arg("--rsh")
+        .arg("false")
+        .status()
+        .unwrap();
+    assert_eq!(status.code(), Some(syncr::network::ErrorCode::Io.exit_code().into()));
+
+    // The remote's own explanation wins over its exit status.
+    let status = sync(&file, &directory.join("root"), "../file.txt", "");
+    assert_eq!(status.code(), Some(syncr::network::ErrorCode::PermissionDenied.exit_code().into()));
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+fn stalled_clients_are_dropped() {
+    let directory = directory("stalled");
+    let mut syncrd = Command::new(env!("CARGO_BIN_EXE_syncrd"))
+        .arg("--server")
+        .arg("--root")
+        .arg(directory.join("root"))
+        .arg("--timeout")
+        .arg("1")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    // Never say anything, but keep the connection open.
+    let _stdin = syncrd.stdin.take();
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+    let status = loop {
+        if let Some(status) = syncrd.try_wait().unwrap() {
+            break status;
+        }
+        assert!(std::time::Instant::now() < deadline, "syncrd is still waiting for the client");
+        std::thread::sleep(std::time::Duration::from_millis(50));
+    };
+    assert!(!status.success());
+    std::fs::remove_dir_all(&directory).unwrap();
+}